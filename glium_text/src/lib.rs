@@ -13,7 +13,7 @@ Usage:
 // The `TextSystem` contains the shaders and elements used for text display.
 let system = glium_text::TextSystem::new(&display);
 
-// Creating a `FontTexture`, which a regular `Texture` which contains the font.
+// Creating a `FontTexture`, which caches the glyphs of the font in textures.
 // Note that loading the systems fonts is not covered by this library.
 let font = glium_text::FontTexture::new(&display, std::fs::File::open(&std::path::Path::new("my_font.ttf")).unwrap(), 24).unwrap();
 
@@ -29,6 +29,22 @@ glium_text::draw(&text, &system, &mut display.draw(), matrix, (1.0, 1.0, 0.0, 1.
 # }
 ```
 
+Many texts using the same font can be drawn with a draw call per page of the atlas, by
+collecting them in a `TextBatch`, each with its own position and color, and drawing it
+with `draw_batch`.
+
+A `FontTexture` built with `FontTexture::with_family` contains the regular, bold, italic
+and bold italic faces of a font family, synthesising those which are missing. Runs of
+text in different styles and colors are then laid out with `TextDisplay::set_runs`; the
+runs without a color of their own are drawn in the color given to `draw`.
+
+The glyphs are rasterised the first time they are laid out, and packed into the pages of
+an atlas, which are added and emptied as needed, so a font of tens of thousands of glyphs
+loads as fast as a small one. The characters missing in the faces of a family are looked
+up in the fallback fonts of the `FontFamily`, in order, then in the font returned for the
+character by its `find_fallback` function, if any. Those which no font has are drawn as a
+box.
+
 */
 
 #![warn(missing_docs)]
@@ -42,16 +58,140 @@ use glium::DrawParameters;
 use glium::backend::Context;
 use glium::backend::Facade;
 use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::default::Default;
+use std::ffi::CString;
 use std::io::Read;
 use std::ops::Deref;
+use std::path::PathBuf;
 use std::rc::Rc;
 
-/// Texture which contains the characters of the font.
+// a margin around each glyph in the pages to prevent artifacts
+const MARGIN: u32 = 2;
+
+// the number of pages past which the least recently drawn page is emptied to make room
+const MAX_PAGES: usize = 4;
+
+// the bounds of the width and height of the pages, in pixels
+const MIN_PAGE_SIZE: u32 = 256;
+const MAX_PAGE_SIZE: u32 = 2048;
+
+// the heights of the shelves of a page are rounded up to a multiple of this, in pixels
+const SHELF_STEP: u32 = 8;
+
+/// A cache of the glyphs of a font, in one or all four styles, and of its fallback fonts.
+///
+/// The glyphs are rasterised the first time they are laid out, and packed into textures
+/// of the same size, the pages of an atlas. A page is added when the others are full, and
+/// past a few pages the one drawn least recently is emptied instead; the texts which used
+/// it are laid out again when they are next drawn.
 pub struct FontTexture {
-    texture: glium::texture::Texture2d,
-    character_infos: Vec<(char, CharacterInfos)>,
+    context: Rc<Context>,
+    library: freetype::FT_Library,
+    faces: Vec<(freetype::FT_Face, FontStyle)>, // indexed by `FontStyle::index`, with what to synthesise
+    _fonts: Vec<Rc<Vec<u8>>>, // the data of the faces, which use it until they are done
+    font_size: u32,
     em_pixels: u32,
+    line_height: f32, // in EMs
+    space_advance: f32, // in pixels
+    find_fallback: Option<FindFallback>,
+    cache: RefCell<GlyphCache>,
+}
+
+/// A function returning a font file which may have a character, called for the characters
+/// which none of the fonts opened so far has.
+pub type FindFallback = Rc<Fn(char) -> Option<PathBuf>>;
+
+// The glyphs rasterised so far, the pages they are in, and the fallback fonts.
+struct GlyphCache {
+    glyphs: HashMap<GlyphKey, Option<Glyph>>, // `None` for the characters which no font has
+    atlas: Atlas,
+    textures: Vec<glium::texture::Texture2d>, // of the pages of the atlas
+    fallback_paths: Vec<PathBuf>, // the given ones, then those returned by `find_fallback`
+    fallback_faces: Vec<freetype::FT_Face>, // of the first paths, null for those which failed to open
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+enum GlyphKey {
+    Char(char, FontStyle),
+    Tofu, // the box drawn for the characters which no font has
+}
+
+#[derive(Copy, Clone)]
+struct Glyph {
+    page: Option<usize>, // `None` for a glyph without pixels, like the space
+    infos: CharacterInfos,
+}
+
+// Where the glyphs are in the pages of the atlas, and which page to empty when they are
+// all full. The textures of the pages follow the changes it reports.
+struct Atlas {
+    page_size: u32,
+    pages: Vec<Page>,
+    frame: u64, // the number of draws so far
+}
+
+// How the atlas made room for a glyph.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum PageChange {
+    Kept, // the glyph fitted in a page
+    Added,
+    Emptied,
+}
+
+// A page of the atlas, filled from the top with rows of glyphs, its shelves.
+struct Page {
+    shelves: Vec<Shelf>,
+    epoch: u32, // incremented when the page is emptied
+    last_used: u64, // the frame in which a text last used the page
+}
+
+struct Shelf {
+    y: u32,
+    height: u32,
+    x: u32, // the end of the glyphs on the shelf
+}
+
+// A rasterised glyph, in pixels.
+struct Bitmap {
+    width: u32,
+    rows: u32,
+    data: Vec<f32>, // the rows from the top, between 0 and 1
+    left: i32, // from the pen position to the left of the bitmap
+    top: i32, // from the baseline to the top of the bitmap
+    advance: f32,
+}
+
+/// The style of a face of a font family.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FontStyle {
+    /// The upright face of normal weight.
+    Regular,
+    /// The upright face of bold weight.
+    Bold,
+    /// The slanted face of normal weight.
+    Italic,
+    /// The slanted face of bold weight.
+    BoldItalic,
+}
+
+/// The font files of a family, given to `FontTexture::with_family`.
+pub struct FontFamily<R> {
+    /// The regular face, which is required.
+    pub regular: R,
+    /// The bold face, synthesised from the regular one if it is missing.
+    pub bold: Option<R>,
+    /// The italic face, synthesised from the regular one if it is missing.
+    pub italic: Option<R>,
+    /// The bold italic face, synthesised from the bold or italic one if it is missing.
+    pub bold_italic: Option<R>,
+    /// The font files consulted, in order, for the characters missing in the faces.
+    /// Their glyphs are drawn in the regular style.
+    pub fallbacks: Vec<PathBuf>,
+    /// Finds a font file for a character which none of the faces and fallbacks has. A font
+    /// is only opened once, and the characters which it lacks are looked up again.
+    pub find_fallback: Option<FindFallback>,
 }
 
 /// Object that contains the elements shared by all `TextDisplay` objects.
@@ -60,22 +200,32 @@ pub struct FontTexture {
 pub struct TextSystem {
     context: Rc<Context>,
     program: glium::Program,
+    batch_program: glium::Program,
 }
 
 /// Object that will allow you to draw a text.
 pub struct TextDisplay<F> where F: Deref<Target=FontTexture> {
     context: Rc<Context>,
     texture: F,
-    vertex_buffer: Option<glium::VertexBuffer<VertexFormat>>,
-    index_buffer: Option<glium::IndexBuffer<u16>>,
+    runs: Vec<(String, FontStyle, Option<(f32, f32, f32, f32)>)>, // kept to lay the text out again
+    quads: RefCell<Vec<PageQuads>>,
     char_pos_x: Vec<f32>,
-    is_empty: bool,
+    tab_width: u32,
+}
+
+// The glyphs of a text which are in a page of the texture.
+struct PageQuads {
+    page: usize,
+    epoch: u32, // of the page when the text was laid out
+    vertices: Vec<VertexFormat>, // four per glyph
+    // built on the first call to `draw`, texts which are only drawn in a batch never need them
+    buffers: Option<(glium::VertexBuffer<VertexFormat>, glium::IndexBuffer<u16>)>,
 }
 
 // structure containing informations about a character of a font
 #[derive(Copy, Clone, Debug)]
 struct CharacterInfos {
-    // coordinates of the character top-left hand corner on the font's texture
+    // coordinates of the character top-left hand corner on its page
     tex_coords: (f32, f32),
 
     // width and height of character in texture units
@@ -117,17 +267,125 @@ impl<'a> glium::texture::Texture2dDataSource<'a> for &'a TextureData {
 struct VertexFormat {
     position: [f32; 2],
     tex_coords: [f32; 2],
+    run_color: [f32; 4],
+    uses_run_color: f32, // 1 if the glyph is drawn in `run_color`, 0 for the color given to `draw`
+}
+
+implement_vertex!(VertexFormat, position, tex_coords, run_color, uses_run_color);
+
+/// A run of text in one style and color, see `TextDisplay::set_runs`.
+#[derive(Copy, Clone, Debug)]
+pub struct TextRun<'a> {
+    /// The text of the run.
+    pub text: &'a str,
+    /// The face the text is drawn in.
+    pub style: FontStyle,
+    /// The color of the text, or `None` for the color given when drawing it.
+    pub color: Option<(f32, f32, f32, f32)>,
+}
+
+/// Collects the glyphs of many texts to draw them with a draw call per page of the atlas.
+///
+/// All the texts in a batch must use the same `FontTexture`, the one given to `draw_batch`.
+pub struct TextBatch {
+    context: Rc<Context>,
+    vertices: Vec<Vec<BatchVertexFormat>>, // indexed by the page of the glyphs
+    vertex_buffer: Option<glium::VertexBuffer<BatchVertexFormat>>, // reused, grown as needed
+    index_buffer: Option<glium::IndexBuffer<u32>>,
+}
+
+#[derive(Copy, Clone)]
+struct BatchVertexFormat {
+    position: [f32; 2],
+    tex_coords: [f32; 2],
+    color: [f32; 4],
+}
+
+implement_vertex!(BatchVertexFormat, position, tex_coords, color);
+
+impl FontStyle {
+    /// Returns the style with the given weight and slant.
+    pub fn new(bold: bool, italic: bool) -> FontStyle {
+        match (bold, italic) {
+            (false, false) => FontStyle::Regular,
+            (true, false) => FontStyle::Bold,
+            (false, true) => FontStyle::Italic,
+            (true, true) => FontStyle::BoldItalic,
+        }
+    }
+
+    /// Returns true for `Bold` and `BoldItalic`.
+    pub fn is_bold(self) -> bool {
+        self == FontStyle::Bold || self == FontStyle::BoldItalic
+    }
+
+    /// Returns true for `Italic` and `BoldItalic`.
+    pub fn is_italic(self) -> bool {
+        self == FontStyle::Italic || self == FontStyle::BoldItalic
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
 }
 
-implement_vertex!(VertexFormat, position, tex_coords);
+impl<R> FontFamily<R> {
+    /// Builds a family of which only the regular face is given.
+    pub fn new(regular: R) -> FontFamily<R> {
+        FontFamily { regular: regular, bold: None, italic: None, bold_italic: None, fallbacks: vec![], find_fallback: None }
+    }
+}
 
 impl FontTexture {
-    /// Creates a new texture representing a font stored in a `FontTexture`.
+    /// Creates a new cache of the glyphs of a font.
+    ///
+    /// The cache only contains the regular style. Text in other styles is drawn with it.
     pub fn new<R, F>(facade: &F, font: R, font_size: u32)
                      -> Result<FontTexture, ()> where R: Read, F: Facade
+    {
+        FontTexture::build(facade, vec![(read_font(font), FontStyle::Regular)], vec![], None, font_size)
+    }
+
+    /// Creates a new cache of the glyphs of the four styles of a font family.
+    ///
+    /// The missing faces are synthesised from the closest given face, by emboldening or
+    /// slanting its glyphs. Synthesised glyphs keep the advance of the original ones, so
+    /// a monospace font stays monospace. The data of each font is read once and shared by
+    /// the faces made from it.
+    pub fn with_family<R, F>(facade: &F, family: FontFamily<R>, font_size: u32)
+                             -> Result<FontTexture, ()> where R: Read, F: Facade
+    {
+        let regular = read_font(family.regular);
+        let bold = family.bold.map(read_font);
+        let italic = family.italic.map(read_font);
+        let bold_italic = family.bold_italic.map(read_font);
+
+        // each face is the font data and what to synthesise on top of it
+        let faces = vec![
+            (regular.clone(), FontStyle::Regular),
+            match bold {
+                Some(ref bold) => (bold.clone(), FontStyle::Regular),
+                None => (regular.clone(), FontStyle::Bold),
+            },
+            match italic {
+                Some(ref italic) => (italic.clone(), FontStyle::Regular),
+                None => (regular.clone(), FontStyle::Italic),
+            },
+            match (bold_italic, bold, italic) {
+                (Some(bold_italic), _, _) => (bold_italic, FontStyle::Regular),
+                (None, Some(bold), _) => (bold, FontStyle::Italic),
+                (None, None, Some(italic)) => (italic, FontStyle::Bold),
+                (None, None, None) => (regular, FontStyle::BoldItalic),
+            },
+        ];
+        FontTexture::build(facade, faces, family.fallbacks, family.find_fallback, font_size)
+    }
+
+    // Builds the cache of the given faces, the first one being the regular face.
+    fn build<F>(facade: &F, fonts: Vec<(Rc<Vec<u8>>, FontStyle)>, fallbacks: Vec<PathBuf>,
+                find_fallback: Option<FindFallback>, font_size: u32) -> Result<FontTexture, ()> where F: Facade
     {
         // building the freetype library
-        // FIXME: call FT_Done_Library
         let library = unsafe {
             // taken from https://github.com/PistonDevelopers/freetype-rs/blob/master/src/library.rs
             extern "C" fn alloc_library(_memory: freetype::FT_Memory, size: libc::c_long) -> *mut libc::c_void {
@@ -164,52 +422,68 @@ impl FontTexture {
             raw
         };
 
-        // building the freetype face object
-        let font: Vec<u8> = font.bytes().map(|c| c.unwrap()).collect();
-
-        let face: freetype::FT_Face = unsafe {
-            let mut face = ::std::ptr::null_mut();
-            let err = freetype::FT_New_Memory_Face(library, font.as_ptr(),
-                                                   font.len() as freetype::FT_Long, 0, &mut face);
-            if err == freetype::FT_Err_Ok {
-                face
-            } else {
-                return Err(());
-            }
-        };
-
-        // computing the list of characters in the font
-        let characters_list = unsafe {
-            // TODO: unresolved symbol
-            /*if freetype::FT_Select_CharMap(face, freetype::FT_ENCODING_UNICODE) != 0 {
-                return Err(());
-            }*/
-
-            let mut result = Vec::new();
-
-            let mut g: freetype::FT_UInt = std::mem::uninitialized();
-            let mut c = freetype::FT_Get_First_Char(face, &mut g);
+        // building the freetype face objects at the right pixel size, which use the font
+        // data until they are done
+        let mut faces = Vec::with_capacity(fonts.len());
+        for &(ref font, synthesis) in &fonts {
+            let face: freetype::FT_Face = unsafe {
+                let mut face = ::std::ptr::null_mut();
+                let err = freetype::FT_New_Memory_Face(library, font.as_ptr(),
+                                                       font.len() as freetype::FT_Long, 0, &mut face);
+                if err == freetype::FT_Err_Ok && freetype::FT_Set_Pixel_Sizes(face, font_size, font_size) == 0 {
+                    face
+                } else {
+                    if err == freetype::FT_Err_Ok {
+                        freetype::FT_Done_Face(face);
+                    }
+                    for &(face, _) in &faces {
+                        freetype::FT_Done_Face(face);
+                    }
+                    freetype::FT_Done_Library(library);
+                    return Err(());
+                }
+            };
+            faces.push((face, synthesis));
+        }
 
-            while g != 0 {
-                result.push(std::mem::transmute(c as u32));     // TODO: better solution?
-                c = freetype::FT_Get_Next_Char(face, c, &mut g);
-            }
+        let (em_pixels, space_advance, line_height) = unsafe {
+            let regular = faces[0].0;
 
-            result
-        };
+            // computing em_pixels, the height of an 'M'
+            // FIXME: this is hacky
+            let em_pixels = match rasterize(regular, 'M', FontStyle::Regular, font_size) {
+                Some(ref bitmap) if bitmap.rows > 0 => bitmap.rows,
+                _ => font_size,
+            };
+            let space_advance = rasterize(regular, ' ', FontStyle::Regular, font_size)
+                .map_or(font_size as f32 / 2.0, |bitmap| bitmap.advance);
 
-        // building the infos
-        let (texture_data, chr_infos, em_pixels) = unsafe {
-            build_font_image(face, characters_list, font_size)
+            // the line height in pixels, in 26.6 fixed point
+            let line_height = (*(*regular).size).metrics.height as f32 / 64.0;
+            (em_pixels, space_advance, line_height / em_pixels as f32)
         };
 
-        // we load the texture in the display
-        let texture = glium::texture::Texture2d::new(facade, &texture_data).unwrap();
+        // a page holds a few hundred glyphs
+        let page_size = get_nearest_po2(font_size * 32);
+        let page_size = std::cmp::min(std::cmp::max(page_size, MIN_PAGE_SIZE), MAX_PAGE_SIZE);
 
         Ok(FontTexture {
-            texture: texture,
-            character_infos: chr_infos,
+            context: facade.get_context().clone(),
+            library: library,
+            faces: faces,
+            _fonts: fonts.into_iter().map(|(font, _)| font).collect(),
+            font_size: font_size,
             em_pixels: em_pixels,
+            line_height: line_height,
+            space_advance: space_advance,
+            find_fallback: find_fallback,
+            cache: RefCell::new(GlyphCache {
+                glyphs: HashMap::new(),
+                atlas: Atlas::new(page_size),
+                textures: vec![],
+                fallback_paths: fallbacks,
+                fallback_faces: vec![],
+            }),
         })
     }
 
@@ -219,6 +493,292 @@ impl FontTexture {
     pub fn em_pixels(&self) -> u32 {
         self.em_pixels
     }
+
+    /// Return the distance between the baselines of two lines of text in em-units, as
+    /// recommended by the font.
+    pub fn line_height(&self) -> f32 {
+        self.line_height
+    }
+
+    /// Return the horizontal advance of a character in em-units, or `None` if no font
+    /// has the character.
+    pub fn char_advance(&self, character: char) -> Option<f32> {
+        self.glyph(FontStyle::Regular, character).map(|glyph| {
+            glyph.infos.left_padding + glyph.infos.size.0 + glyph.infos.right_padding
+        })
+    }
+
+    // Returns the glyph of a character in the given style, from the face of the style,
+    // else from the regular face, else from the first fallback font which has it, else from
+    // the font found for it by `find_fallback`. Returns `None` if no font has the character.
+    fn glyph(&self, style: FontStyle, character: char) -> Option<Glyph> {
+        self.cached_glyph(GlyphKey::Char(character, style))
+    }
+
+    // Returns the box drawn for the characters which no font has.
+    fn tofu(&self) -> Option<Glyph> {
+        self.cached_glyph(GlyphKey::Tofu)
+    }
+
+    // Returns a glyph, rasterising it and adding it to a page the first time.
+    fn cached_glyph(&self, key: GlyphKey) -> Option<Glyph> {
+        let mut cache = self.cache.borrow_mut();
+        if let Some(&glyph) = cache.glyphs.get(&key) {
+            if let Some(Glyph { page: Some(page), .. }) = glyph {
+                cache.atlas.touch(page);
+            }
+            return glyph;
+        }
+        let glyph = unsafe { self.rasterize(&mut cache, key) }.and_then(|bitmap| self.insert(&mut cache, bitmap));
+        cache.glyphs.insert(key, glyph);
+        glyph
+    }
+
+    unsafe fn rasterize(&self, cache: &mut GlyphCache, key: GlyphKey) -> Option<Bitmap> {
+        let (character, style) = match key {
+            GlyphKey::Char(character, style) => (character, style),
+            GlyphKey::Tofu => return Some(self.tofu_bitmap()),
+        };
+        let has_character = |face| freetype::FT_Get_Char_Index(face, character as freetype::FT_ULong) != 0;
+
+        let (face, synthesis) = *self.faces.get(style.index()).unwrap_or(&self.faces[0]);
+        if has_character(face) {
+            return rasterize(face, character, synthesis, self.font_size);
+        }
+        let regular = self.faces[0].0;
+        if has_character(regular) {
+            return rasterize(regular, character, FontStyle::Regular, self.font_size);
+        }
+        let mut index = 0;
+        while let Some(face) = cache.fallback_face(index, self.library, self.font_size) {
+            if !face.is_null() && has_character(face) {
+                return rasterize(face, character, FontStyle::Regular, self.font_size);
+            }
+            index += 1;
+        }
+
+        // a font which was already opened does not have the character
+        let path = match self.find_fallback.as_ref().and_then(|find| find(character)) {
+            Some(ref path) if cache.fallback_paths.contains(path) => return None,
+            Some(path) => path,
+            None => return None,
+        };
+        cache.fallback_paths.push(path);
+        match cache.fallback_face(index, self.library, self.font_size) {
+            Some(face) if !face.is_null() && has_character(face) => {
+                rasterize(face, character, FontStyle::Regular, self.font_size)
+            },
+            _ => None,
+        }
+    }
+
+    // Draws the tofu, a box as wide as a space and as high as an 'M', with a margin on
+    // both sides.
+    fn tofu_bitmap(&self) -> Bitmap {
+        let padding = (self.space_advance / 8.0).round().max(1.0);
+        let width = std::cmp::max((self.space_advance - 2.0 * padding) as u32, 3);
+        let rows = std::cmp::max(self.em_pixels, 3);
+        let stroke = std::cmp::max(self.font_size / 16, 1);
+        let mut data = Vec::with_capacity((width * rows) as usize);
+        for y in 0 .. rows {
+            for x in 0 .. width {
+                let edge = x < stroke || x >= width - stroke || y < stroke || y >= rows - stroke;
+                data.push(if edge { 1.0 } else { 0.0 });
+            }
+        }
+        Bitmap {
+            width: width,
+            rows: rows,
+            data: data,
+            left: padding as i32,
+            top: rows as i32,
+            advance: self.space_advance,
+        }
+    }
+
+    // Copies a bitmap into a page and returns its glyph, or `None` if it is larger than
+    // a page.
+    fn insert(&self, cache: &mut GlyphCache, bitmap: Bitmap) -> Option<Glyph> {
+        let em_pixels = self.em_pixels as f32;
+        let mut infos = CharacterInfos {
+            tex_coords: (0.0, 0.0),
+            tex_size: (0.0, 0.0),
+            size: (bitmap.width as f32 / em_pixels, bitmap.rows as f32 / em_pixels),
+            height_over_line: bitmap.top as f32 / em_pixels,
+            left_padding: bitmap.left as f32 / em_pixels,
+            right_padding: (bitmap.advance - bitmap.width as f32 - bitmap.left as f32) / em_pixels,
+        };
+        if bitmap.width == 0 || bitmap.rows == 0 {
+            return Some(Glyph { page: None, infos: infos });
+        }
+
+        let (page, (x, y)) = self.allocate(cache, bitmap.width, bitmap.rows)?;
+        let rect = glium::Rect { left: x, bottom: y, width: bitmap.width, height: bitmap.rows };
+        cache.textures[page].write(rect, &TextureData {
+            data: bitmap.data,
+            width: bitmap.width,
+            height: bitmap.rows,
+        });
+
+        // the texture coordinates are in the (0,1) range
+        let page_size = cache.atlas.page_size as f32;
+        infos.tex_coords = (x as f32 / page_size, y as f32 / page_size);
+        infos.tex_size = (bitmap.width as f32 / page_size, bitmap.rows as f32 / page_size);
+        Some(Glyph { page: Some(page), infos: infos })
+    }
+
+    // Finds room for a bitmap in a page, adding or emptying the texture of a page as the
+    // atlas did.
+    fn allocate(&self, cache: &mut GlyphCache, width: u32, height: u32) -> Option<(usize, (u32, u32))> {
+        let (page, position, change) = cache.atlas.allocate(width, height)?;
+        match change {
+            PageChange::Kept => (),
+            PageChange::Added => {
+                let page_size = cache.atlas.page_size;
+                let texture = glium::texture::Texture2d::new(&self.context, &TextureData::empty(page_size)).unwrap();
+                cache.textures.push(texture);
+            },
+            PageChange::Emptied => cache.clear_page(page),
+        }
+        Some((page, position))
+    }
+
+    // Marks the pages used so far as free to be emptied.
+    fn end_frame(&self) {
+        self.cache.borrow_mut().atlas.frame += 1;
+    }
+}
+
+impl Drop for FontTexture {
+    fn drop(&mut self) {
+        let cache = self.cache.borrow();
+        unsafe {
+            for &(face, _) in &self.faces {
+                freetype::FT_Done_Face(face);
+            }
+            for &face in cache.fallback_faces.iter().filter(|face| !face.is_null()) {
+                freetype::FT_Done_Face(face);
+            }
+            freetype::FT_Done_Library(self.library);
+        }
+    }
+}
+
+impl GlyphCache {
+    // Returns the face of the fallback font at the given index, opening it if it is the
+    // first time, or `None` past the last font. The face is null if the font failed to open.
+    unsafe fn fallback_face(&mut self, index: usize, library: freetype::FT_Library, font_size: u32)
+                            -> Option<freetype::FT_Face>
+    {
+        while self.fallback_faces.len() <= index {
+            let path = self.fallback_paths.get(self.fallback_faces.len())?;
+            let mut face = std::ptr::null_mut();
+            let opened = match CString::new(path.to_string_lossy().into_owned()) {
+                Ok(path) => freetype::FT_New_Face(library, path.as_ptr(), 0, &mut face) == freetype::FT_Err_Ok,
+                Err(_) => false,
+            };
+            if opened && freetype::FT_Set_Pixel_Sizes(face, font_size, font_size) != 0 {
+                // a bitmap font without that size
+                freetype::FT_Done_Face(face);
+                face = std::ptr::null_mut();
+            }
+            self.fallback_faces.push(if opened { face } else { std::ptr::null_mut() });
+        }
+        Some(self.fallback_faces[index])
+    }
+
+    // Empties the texture of a page which the atlas emptied, forgetting the glyphs in it.
+    // The texts which use the page see that its epoch changed.
+    fn clear_page(&mut self, index: usize) {
+        let page_size = self.atlas.page_size;
+        let rect = glium::Rect { left: 0, bottom: 0, width: page_size, height: page_size };
+        self.textures[index].write(rect, &TextureData::empty(page_size));
+        self.glyphs.retain(|_, glyph| glyph.map_or(true, |glyph| glyph.page != Some(index)));
+    }
+}
+
+impl Atlas {
+    fn new(page_size: u32) -> Atlas {
+        Atlas { page_size: page_size, pages: vec![], frame: 0 }
+    }
+
+    // Finds room for a rectangle in a page and returns the page, the position in it and
+    // how the room was made. When all the pages are full, a page is added, or past
+    // `MAX_PAGES`, the least recently drawn page is emptied. The pages used since the last
+    // draw are never emptied, as the texts in a batch being drawn use them. Returns `None`
+    // if the rectangle is larger than a page.
+    fn allocate(&mut self, width: u32, height: u32) -> Option<(usize, (u32, u32), PageChange)> {
+        let (page_size, frame) = (self.page_size, self.frame);
+        if Page::new(frame).allocate(width, height, page_size).is_none() {
+            return None;
+        }
+        for (index, page) in self.pages.iter_mut().enumerate() {
+            if let Some(position) = page.allocate(width, height, page_size) {
+                page.last_used = frame;
+                return Some((index, position, PageChange::Kept));
+            }
+        }
+
+        let unused = self.pages.iter().enumerate()
+            .filter(|&(_, page)| page.last_used < frame)
+            .min_by_key(|&(_, page)| page.last_used)
+            .map(|(index, _)| index);
+        let (index, change) = match unused {
+            Some(index) if self.pages.len() >= MAX_PAGES => {
+                let page = &mut self.pages[index];
+                page.shelves.clear();
+                page.epoch += 1;
+                page.last_used = frame;
+                (index, PageChange::Emptied)
+            },
+            _ => {
+                self.pages.push(Page::new(frame));
+                (self.pages.len() - 1, PageChange::Added)
+            },
+        };
+        self.pages[index].allocate(width, height, page_size).map(|position| (index, position, change))
+    }
+
+    // Marks a page as used by a text being drawn.
+    fn touch(&mut self, page: usize) {
+        self.pages[page].last_used = self.frame;
+    }
+}
+
+impl Page {
+    fn new(frame: u64) -> Page {
+        Page { shelves: vec![], epoch: 0, last_used: frame }
+    }
+
+    // Returns the position of the top-left hand corner of a free rectangle of the given
+    // size, on the lowest shelf high enough which has room left, or else on a new shelf.
+    // Returns `None` if the page is full.
+    fn allocate(&mut self, width: u32, height: u32, page_size: u32) -> Option<(u32, u32)> {
+        let (width, height) = (width + MARGIN, height + MARGIN);
+        let shelf = self.shelves.iter_mut()
+            .filter(|shelf| shelf.height >= height && shelf.x + width <= page_size)
+            .min_by_key(|shelf| shelf.height);
+        if let Some(shelf) = shelf {
+            let position = (shelf.x, shelf.y);
+            shelf.x += width;
+            return Some(position);
+        }
+
+        let y = self.shelves.last().map_or(MARGIN, |shelf| shelf.y + shelf.height);
+        let shelf_height = (height + SHELF_STEP - 1) / SHELF_STEP * SHELF_STEP;
+        if MARGIN + width > page_size || y + shelf_height > page_size {
+            return None;
+        }
+        self.shelves.push(Shelf { y: y, height: shelf_height, x: MARGIN + width });
+        Some((MARGIN, y))
+    }
+}
+
+impl TextureData {
+    // Returns the data of an empty square texture.
+    fn empty(size: u32) -> TextureData {
+        TextureData { data: vec![0.0; (size * size) as usize], width: size, height: size }
+    }
 }
 
 /*impl glium::uniforms::AsUniformValue for FontTexture {
@@ -232,7 +792,7 @@ impl TextSystem {
     pub fn new<F>(facade: &F) -> TextSystem where F: Facade {
         TextSystem {
             context: facade.get_context().clone(),
-            program: program!(facade,
+            batch_program: program!(facade,
                 140 => {
                     vertex: "
                         #version 140
@@ -240,22 +800,25 @@ impl TextSystem {
                         uniform mat4 matrix;
                         in vec2 position;
                         in vec2 tex_coords;
+                        in vec4 color;
 
                         out vec2 v_tex_coords;
+                        out vec4 v_color;
 
                         void main() {
                             gl_Position = matrix * vec4(position, 0.0, 1.0);
                             v_tex_coords = tex_coords;
+                            v_color = color;
                         }
                     ",
                     fragment: "
                         #version 140
                         in vec2 v_tex_coords;
+                        in vec4 v_color;
                         out vec4 f_color;
-                        uniform vec4 color;
                         uniform sampler2D tex;
                         void main() {
-                            vec4 c = vec4(color.rgb, color.a * texture(tex, v_tex_coords));
+                            vec4 c = vec4(v_color.rgb, v_color.a * texture(tex, v_tex_coords));
                             if (c.a <= 0.01) {
                                 discard;
                             } else {
@@ -271,23 +834,100 @@ impl TextSystem {
 
                         attribute vec2 position;
                         attribute vec2 tex_coords;
+                        attribute vec4 color;
                         varying vec2 v_tex_coords;
+                        varying vec4 v_color;
                         uniform mat4 matrix;
 
                         void main() {
                             gl_Position = matrix * vec4(position.x, position.y, 0.0, 1.0);
                             v_tex_coords = tex_coords;
+                            v_color = color;
+                        }
+                    ",
+                    fragment: "
+                        #version 110
+
+                        varying vec2 v_tex_coords;
+                        varying vec4 v_color;
+                        uniform sampler2D tex;
+
+                        void main() {
+                            gl_FragColor = vec4(v_color.rgb, v_color.a * texture2D(tex, v_tex_coords));
+                            if (gl_FragColor.a <= 0.01) {
+                                discard;
+                            }
+                        }
+                    "
+                },
+
+            ).unwrap(),
+            program: program!(facade,
+                140 => {
+                    vertex: "
+                        #version 140
+
+                        uniform mat4 matrix;
+                        uniform vec4 color;
+                        in vec2 position;
+                        in vec2 tex_coords;
+                        in vec4 run_color;
+                        in float uses_run_color;
+
+                        out vec2 v_tex_coords;
+                        out vec4 v_color;
+
+                        void main() {
+                            gl_Position = matrix * vec4(position, 0.0, 1.0);
+                            v_tex_coords = tex_coords;
+                            v_color = mix(color, run_color, uses_run_color);
                         }
                     ",
                     fragment: "
+                        #version 140
+                        in vec2 v_tex_coords;
+                        in vec4 v_color;
+                        out vec4 f_color;
+                        uniform sampler2D tex;
+                        void main() {
+                            vec4 c = vec4(v_color.rgb, v_color.a * texture(tex, v_tex_coords));
+                            if (c.a <= 0.01) {
+                                discard;
+                            } else {
+                                f_color = c;
+                            }
+                        }
+                    "
+                },
+
+                110 => {
+                    vertex: "
                         #version 110
 
+                        attribute vec2 position;
+                        attribute vec2 tex_coords;
+                        attribute vec4 run_color;
+                        attribute float uses_run_color;
                         varying vec2 v_tex_coords;
+                        varying vec4 v_color;
+                        uniform mat4 matrix;
                         uniform vec4 color;
+
+                        void main() {
+                            gl_Position = matrix * vec4(position.x, position.y, 0.0, 1.0);
+                            v_tex_coords = tex_coords;
+                            v_color = mix(color, run_color, uses_run_color);
+                        }
+                    ",
+                    fragment: "
+                        #version 110
+
+                        varying vec2 v_tex_coords;
+                        varying vec4 v_color;
                         uniform sampler2D tex;
 
                         void main() {
-                            gl_FragColor = vec4(color.rgb, color.a * texture2D(tex, v_tex_coords));
+                            gl_FragColor = vec4(v_color.rgb, v_color.a * texture2D(tex, v_tex_coords));
                             if (gl_FragColor.a <= 0.01) {
                                 discard;
                             }
@@ -306,10 +946,10 @@ impl<F> TextDisplay<F> where F: Deref<Target=FontTexture> {
         let mut text_display = TextDisplay {
             context: system.context.clone(),
             texture: texture,
-            vertex_buffer: None,
-            index_buffer: None,
+            runs: vec![],
+            quads: RefCell::new(vec![]),
             char_pos_x: vec![],
-            is_empty: true,
+            tab_width: 4,
         };
 
         text_display.set_text(text);
@@ -319,51 +959,82 @@ impl<F> TextDisplay<F> where F: Deref<Target=FontTexture> {
 
     /// Return the x-positions (in em-units) of the breaks between characters.
     /// When a character starts at n-th byte, then get_char_pos_x()[n] is the x-pos of the character.
-    /// The last value of the array is the x-pos of the end of the string
+    /// The remaining bytes of a multi-byte character are positioned at its end.
+    /// The array has one more element than the string has bytes, the last value is the
+    /// x-pos of the end of the string. Control characters missing in the font have zero
+    /// width, and the other characters which no font has are as wide as a box.
     pub fn get_char_pos_x(&self) -> &[f32] {
         &self.char_pos_x
     }
 
+    /// Sets the distance between tab stops, in widths of the space character.
+    /// The default is 4. Takes effect at the next call to `set_text`.
+    pub fn set_tab_width(&mut self, tab_width: u32) {
+        self.tab_width = tab_width;
+    }
+
     /// Modifies the text on this display.
+    /// Tab characters advance to the next tab stop.
     pub fn set_text(&mut self, text: &str) {
-        self.is_empty = true;
-        self.char_pos_x = vec![0.];
-        self.vertex_buffer = None;
-        self.index_buffer = None;
+        self.set_styled_text(&[(text, FontStyle::Regular)]);
+    }
 
-        // returning if no text
-        if text.len() == 0 {
-            return;
-        }
+    /// Modifies the text on this display, given as runs of text drawn in the face of
+    /// the given style each.
+    pub fn set_styled_text(&mut self, runs: &[(&str, FontStyle)]) {
+        let runs: Vec<TextRun> = runs.iter().map(|&(text, style)| {
+            TextRun { text: text, style: style, color: None }
+        }).collect();
+        self.set_runs(&runs);
+    }
+
+    /// Modifies the text on this display, given as runs of text with a style and maybe
+    /// a color each. The runs without a color are drawn in the color given to `draw`
+    /// or `TextBatch::push`. The positions returned by `get_char_pos_x` are those of
+    /// the whole text.
+    pub fn set_runs(&mut self, runs: &[TextRun]) {
+        self.runs = runs.iter().map(|run| (run.text.to_string(), run.style, run.color)).collect();
+        let (quads, char_pos_x) = self.lay_out();
+        self.quads = RefCell::new(quads);
+        self.char_pos_x = char_pos_x;
+    }
 
-        // these arrays will contain the vertex buffer and index buffer data
-        let mut vertex_buffer_data = Vec::with_capacity(text.len() * 4 * 4);
-        let mut index_buffer_data = Vec::with_capacity(text.len() * 6);
+    // Lays the runs out, rasterising the glyphs which are not in the texture yet. Returns
+    // the glyphs by page and the x-positions of the characters.
+    fn lay_out(&self) -> (Vec<PageQuads>, Vec<f32>) {
+        let mut quads: Vec<PageQuads> = vec![];
+        let mut char_pos_x = vec![0.];
 
-        // iterating over the characters of the string
+        // tab stops are multiples of this
+        let tab_stop = self.texture.char_advance(' ').unwrap_or(0.5) * self.tab_width as f32;
+
+        // iterating over the characters of the runs
         let mut pos_x = 0.;
-        for character in text.chars() {     // FIXME: wrong, but only thing stable
+        let characters = self.runs.iter().flat_map(|&(ref text, style, color)| text.chars().map(move |c| (c, style, color)));
+        for (character, style, color) in characters {     // FIXME: wrong, but only thing stable
+
+            if character == '\t' && tab_stop > 0. {
+                // the epsilon keeps a tab which starts exactly at a tab stop from collapsing
+                pos_x = ((pos_x / tab_stop + 1e-4).floor() + 1.) * tab_stop;
+                char_pos_x.push(pos_x);
+                continue;
+            }
 
-            let infos = match self.texture.character_infos
-                .iter().find(|&&(chr, _)| chr == character)
-            {
-                Some(infos) => infos,
-                None => continue        // character not found in the font, ignoring it
+            // the box shows that a character is there, though no font can draw it
+            let glyph = self.texture.glyph(style, character).or_else(|| {
+                if character.is_control() { None } else { self.texture.tofu() }
+            });
+            let glyph = match glyph {
+                Some(glyph) => glyph,
+                None => {
+                    // a control character not found in the font takes no space
+                    for _ in 0..character.len_utf8() {
+                        char_pos_x.push(pos_x);
+                    }
+                    continue
+                }
             };
-            let infos = infos.1;
-
-            self.is_empty = false;
-
-            // adding the quad in the index buffer
-            {
-                let first_vertex_offset = vertex_buffer_data.len() as u16;
-                index_buffer_data.push(first_vertex_offset);
-                index_buffer_data.push(first_vertex_offset + 1);
-                index_buffer_data.push(first_vertex_offset + 2);
-                index_buffer_data.push(first_vertex_offset + 2);
-                index_buffer_data.push(first_vertex_offset + 1);
-                index_buffer_data.push(first_vertex_offset + 3);
-            }
+            let infos = glyph.infos;
 
             //
             pos_x += infos.left_padding;
@@ -374,51 +1045,145 @@ impl<F> TextDisplay<F> where F: Deref<Target=FontTexture> {
             let top_coord = infos.height_over_line;
             let bottom_coord = infos.height_over_line - infos.size.1;
 
+            // going to next char
+            pos_x = right_coord + infos.right_padding;
+            for _ in 0..character.len_utf8() {
+                char_pos_x.push(pos_x);
+            }
+
+            // the glyphs without pixels are not drawn
+            let page = match glyph.page {
+                Some(page) => page,
+                None => continue,
+            };
+            let index = match quads.iter().position(|quads| quads.page == page) {
+                Some(index) => index,
+                None => {
+                    let epoch = self.texture.cache.borrow().atlas.pages[page].epoch;
+                    quads.push(PageQuads { page: page, epoch: epoch, vertices: vec![], buffers: None });
+                    quads.len() - 1
+                }
+            };
+            let vertices = &mut quads[index].vertices;
+
+            let (run_color, uses_run_color) = match color {
+                Some(color) => ([color.0, color.1, color.2, color.3], 1.0),
+                None => ([0.0; 4], 0.0),
+            };
+
             // top-left vertex
-            vertex_buffer_data.push(VertexFormat {
+            vertices.push(VertexFormat {
                 position: [left_coord, top_coord],
+                run_color: run_color,
+                uses_run_color: uses_run_color,
                 tex_coords: [infos.tex_coords.0, infos.tex_coords.1],
             });
 
             // top-right vertex
-            vertex_buffer_data.push(VertexFormat {
+            vertices.push(VertexFormat {
                 position: [right_coord, top_coord],
+                run_color: run_color,
+                uses_run_color: uses_run_color,
                 tex_coords: [infos.tex_coords.0 + infos.tex_size.0, infos.tex_coords.1],
             });
 
             // bottom-left vertex
-            vertex_buffer_data.push(VertexFormat {
+            vertices.push(VertexFormat {
                 position: [left_coord, bottom_coord],
+                run_color: run_color,
+                uses_run_color: uses_run_color,
                 tex_coords: [infos.tex_coords.0, infos.tex_coords.1 + infos.tex_size.1],
             });
 
             // bottom-right vertex
-            vertex_buffer_data.push(VertexFormat {
+            vertices.push(VertexFormat {
                 position: [right_coord, bottom_coord],
+                run_color: run_color,
+                uses_run_color: uses_run_color,
                 tex_coords: [
                     infos.tex_coords.0 + infos.tex_size.0,
                     infos.tex_coords.1 + infos.tex_size.1
                 ],
             });
+        }
 
-            // going to next char
-            pos_x = right_coord + infos.right_padding;
-            for _ in 0..character.len_utf8() {
-                self.char_pos_x.push(pos_x);
+        (quads, char_pos_x)
+    }
+
+    // Lays the text out again if a page it uses was emptied since it was laid out, and
+    // marks its pages as used by a text being drawn.
+    fn refresh(&self) {
+        let stale = {
+            let mut cache = self.texture.cache.borrow_mut();
+            let mut stale = false;
+            for quads in self.quads.borrow().iter() {
+                cache.atlas.touch(quads.page);
+                stale |= cache.atlas.pages[quads.page].epoch != quads.epoch;
+            }
+            stale
+        };
+        if stale {
+            *self.quads.borrow_mut() = self.lay_out().0;
+        }
+    }
+}
+
+impl TextBatch {
+    /// Builds a new, empty batch.
+    pub fn new(system: &TextSystem) -> TextBatch {
+        TextBatch {
+            context: system.context.clone(),
+            vertices: vec![],
+            vertex_buffer: None,
+            index_buffer: None,
+        }
+    }
+
+    /// Adds a text to the batch. A point (x, y) of the text is drawn at
+    /// `(position.0 + x * scale, position.1 + y * scale)` transformed by the matrix given
+    /// to `draw_batch`. The runs of the text without a color of their own are drawn in
+    /// `color`.
+    pub fn push<F>(&mut self, text: &TextDisplay<F>, position: (f32, f32), scale: f32,
+                   color: (f32, f32, f32, f32)) where F: Deref<Target=FontTexture>
+    {
+        let color = [color.0, color.1, color.2, color.3];
+        text.refresh();
+        for quads in text.quads.borrow().iter() {
+            while self.vertices.len() <= quads.page {
+                self.vertices.push(vec![]);
             }
+            self.vertices[quads.page].extend(quads.vertices.iter().map(|v| BatchVertexFormat {
+                position: [position.0 + v.position[0] * scale, position.1 + v.position[1] * scale],
+                tex_coords: v.tex_coords,
+                color: if v.uses_run_color > 0.5 { v.run_color } else { color },
+            }));
+        }
+    }
+
+    /// Removes all texts from the batch.
+    pub fn clear(&mut self) {
+        for vertices in &mut self.vertices {
+            vertices.clear();
         }
+    }
 
-        if !vertex_buffer_data.len() != 0 {
-            // building the vertex buffer
-            self.vertex_buffer = Some(glium::VertexBuffer::new(&self.context,
-                                                               &vertex_buffer_data).unwrap());
+    /// Returns true if there is nothing to draw.
+    pub fn is_empty(&self) -> bool {
+        self.vertices.iter().all(|vertices| vertices.is_empty())
+    }
+}
 
-            // building the index buffer
-            self.index_buffer = Some(glium::IndexBuffer::new(&self.context,
-                                     glium::index::PrimitiveType::TrianglesList,
-                                     &index_buffer_data).unwrap());
+// Returns the indices of the two triangles of each of `quads` quads of four vertices.
+fn quad_indices<I>(quads: usize) -> Vec<I> where I: From<u16> + ::std::ops::Add<Output=I> + Copy {
+    let mut indices = Vec::with_capacity(quads * 6);
+    let mut first = I::from(0);
+    for _ in 0..quads {
+        for &i in &[0, 1, 2, 2, 1, 3] {
+            indices.push(first + I::from(i));
         }
+        first = first + I::from(4);
     }
+    indices
 }
 
 ///
@@ -435,188 +1200,177 @@ pub fn draw<F, S: ?Sized, M>(text: &TextDisplay<F>, system: &TextSystem, target:
                                    F: Deref<Target=FontTexture>
 {
     let matrix = matrix.into();
-
-    let &TextDisplay { ref vertex_buffer, ref index_buffer, ref texture, is_empty, .. } = text;
     let color = [color.0, color.1, color.2, color.3];
 
-    // returning if nothing to draw
-    if is_empty || vertex_buffer.is_none() || index_buffer.is_none() {
-        return;
-    }
-
-    let vertex_buffer = vertex_buffer.as_ref().unwrap();
-    let index_buffer = index_buffer.as_ref().unwrap();
-
-    let uniforms = uniform! {
-        matrix: matrix,
-        color: color,
-        tex: glium::uniforms::Sampler(&texture.texture, glium::uniforms::SamplerBehavior {
-            magnify_filter: glium::uniforms::MagnifySamplerFilter::Linear,
-            minify_filter: glium::uniforms::MinifySamplerFilter::Linear,
-            .. Default::default()
-        })
-    };
-
-
-    let params = {
-        use glium::BlendingFunction::Addition;
-        use glium::LinearBlendingFactor::*;
-
-        let blending_function = Addition {
-            source: SourceAlpha,
-            destination: OneMinusSourceAlpha
-        };
-
-        let blend = glium::Blend {
-            color: blending_function,
-            alpha: blending_function,
-            constant_value: (1.0, 1.0, 1.0, 1.0),
-        };
+    text.refresh();
+    {
+        let mut quads = text.quads.borrow_mut();
+        let cache = text.texture.cache.borrow();
+
+        // one draw call per page
+        for quads in quads.iter_mut() {
+            if quads.buffers.is_none() {
+                let vertex_buffer = glium::VertexBuffer::new(&text.context, &quads.vertices).unwrap();
+                let index_buffer = glium::IndexBuffer::new(&text.context,
+                                                           glium::index::PrimitiveType::TrianglesList,
+                                                           &quad_indices::<u16>(quads.vertices.len() / 4)).unwrap();
+                quads.buffers = Some((vertex_buffer, index_buffer));
+            }
+            let (ref vertex_buffer, ref index_buffer) = *quads.buffers.as_ref().unwrap();
 
-        DrawParameters {
-            blend: blend,
-            .. Default::default()
+            let uniforms = uniform! {
+                matrix: matrix,
+                color: color,
+                tex: sampler(&cache.textures[quads.page]),
+            };
+            target.draw(vertex_buffer, index_buffer, &system.program, &uniforms,
+                        &draw_parameters()).unwrap();
         }
-    };
-    target.draw(vertex_buffer, index_buffer, &system.program, &uniforms,
-                &params).unwrap();
+    }
+    text.texture.end_frame();
 }
 
-unsafe fn build_font_image(face: freetype::FT_Face, characters_list: Vec<char>, font_size: u32)
-                           -> (TextureData, Vec<(char, CharacterInfos)>, u32)
+/// Draws all the texts of a batch with a draw call per page of the texture, and empties
+/// the batch.
+///
+/// `texture` must be the font of the texts. The matrix is applied to the positions of
+/// the glyphs, as given to `TextBatch::push`.
+pub fn draw_batch<S: ?Sized, M>(batch: &mut TextBatch, system: &TextSystem, texture: &FontTexture,
+                                target: &mut S, matrix: M)
+                                where S: glium::Surface, M: Into<[[f32; 4]; 4]>
 {
-    use std::iter;
-
-    // a margin around each character to prevent artifacts
-    const MARGIN: u32 = 2;
-
-    // setting the right pixel size
-    if freetype::FT_Set_Pixel_Sizes(face, font_size, font_size) != 0 {
-        panic!();
+    let len = batch.vertices.iter().map(|vertices| vertices.len()).sum::<usize>();
+    if len == 0 {
+        return;
     }
+    let matrix = matrix.into();
 
-    // this variable will store the texture data
-    // we set an arbitrary capacity that we think will match what we will need
-    let mut texture_data: Vec<f32> = Vec::with_capacity(characters_list.len() *
-                                                        font_size as usize * font_size as usize);
-
-    // the width is chosen more or less arbitrarily, because we can store everything as long as
-    //  the texture is at least as wide as the widest character
-    // we just try to estimate a width so that width ~= height
-    let texture_width = get_nearest_po2(std::cmp::max(font_size * 2 as u32,
-        ((((characters_list.len() as u32) * font_size * font_size) as f32).sqrt()) as u32));
-
-    // we store the position of the "cursor" in the destination texture
-    // this cursor points to the top-left pixel of the next character to write on the texture
-    let mut cursor_offset = (0u32, 0u32);
+    // the buffers are kept between the calls and grown when needed
+    if batch.vertex_buffer.as_ref().map_or(true, |b| b.len() < len) {
+        let capacity = len.next_power_of_two();
+        batch.vertex_buffer = Some(glium::VertexBuffer::empty_dynamic(&batch.context, capacity).unwrap());
+        batch.index_buffer = Some(glium::IndexBuffer::new(&batch.context,
+                                  glium::index::PrimitiveType::TrianglesList,
+                                  &quad_indices::<u32>(capacity / 4)).unwrap());
+    }
 
-    // number of rows to skip at next carriage return
-    let mut rows_to_skip = 0u32;
+    // the vertices of the pages follow each other in the buffer
+    let mut ranges = Vec::with_capacity(batch.vertices.len());
+    let mut start = 0;
+    for (page, vertices) in batch.vertices.iter().enumerate().filter(|&(_, vertices)| !vertices.is_empty()) {
+        let end = start + vertices.len();
+        batch.vertex_buffer.as_ref().unwrap().slice(start .. end).unwrap().write(&vertices[..]);
+        ranges.push((page, start, end));
+        start = end;
+    }
 
-    // now looping through the list of characters, filling the texture and returning the informations
-    let mut em_pixels = font_size;
-    let mut characters_infos: Vec<(char, CharacterInfos)> = characters_list.into_iter().filter_map(|character| {
-        // loading wanted glyph in the font face
-        if freetype::FT_Load_Glyph(face, freetype::FT_Get_Char_Index(face, character as freetype::FT_ULong), freetype::FT_LOAD_RENDER) != 0 {
-            return None;
+    {
+        let cache = texture.cache.borrow();
+        for &(page, start, end) in &ranges {
+            let vertex_buffer = batch.vertex_buffer.as_ref().unwrap().slice(0 .. len).unwrap();
+            let index_buffer = batch.index_buffer.as_ref().unwrap().slice(start / 4 * 6 .. end / 4 * 6).unwrap();
+            let uniforms = uniform! {
+                matrix: matrix,
+                tex: sampler(&cache.textures[page]),
+            };
+            target.draw(vertex_buffer, index_buffer, &system.batch_program, &uniforms,
+                        &draw_parameters()).unwrap();
         }
-        let bitmap = &(*(*face).glyph).bitmap;
+    }
+    batch.clear();
+    texture.end_frame();
+}
 
-        // adding a left margin before our character to prevent artifacts
-        cursor_offset.0 += MARGIN;
+fn read_font<R: Read>(mut font: R) -> Rc<Vec<u8>> {
+    let mut data = vec![];
+    font.read_to_end(&mut data).unwrap();
+    Rc::new(data)
+}
 
-        // computing em_pixels
-        // FIXME: this is hacky
-        if character == 'M' {
-            // println!("M  [{}x{}] bitmap: {:?}", bitmap.width, bitmap.rows, std::slice::from_raw_parts(bitmap.buffer, (bitmap.rows * bitmap.width) as usize));
-            em_pixels = bitmap.rows as u32;
-        }
+fn sampler<'a>(texture: &'a glium::texture::Texture2d) -> glium::uniforms::Sampler<'a, glium::texture::Texture2d> {
+    glium::uniforms::Sampler(texture, glium::uniforms::SamplerBehavior {
+        magnify_filter: glium::uniforms::MagnifySamplerFilter::Linear,
+        minify_filter: glium::uniforms::MinifySamplerFilter::Linear,
+        .. Default::default()
+    })
+}
 
-        // carriage return our cursor if we don't have enough room to write the next caracter
-        // we add a margin to prevent artifacts
-        if cursor_offset.0 + (bitmap.width as u32) + MARGIN >= texture_width {
-            assert!(bitmap.width as u32 <= texture_width);       // if this fails, we should increase texture_width
-            cursor_offset.0 = 0;
-            cursor_offset.1 += rows_to_skip;
-            rows_to_skip = 0;
-        }
+fn draw_parameters<'a>() -> DrawParameters<'a> {
+    use glium::BlendingFunction::Addition;
+    use glium::LinearBlendingFactor::*;
 
-        // if the texture data buffer has not enough lines, adding some
-        if rows_to_skip < MARGIN + bitmap.rows as u32 {
-            let diff = MARGIN + (bitmap.rows as u32) - rows_to_skip;
-            rows_to_skip = MARGIN + bitmap.rows as u32;
-            texture_data.extend(iter::repeat(0.0).take((diff * texture_width) as usize));
-        }
+    let blending_function = Addition {
+        source: SourceAlpha,
+        destination: OneMinusSourceAlpha
+    };
 
-        // copying the data to the texture
-        let offset_x_before_copy = cursor_offset.0;
-        if bitmap.rows >= 1 {
-            let destination = &mut texture_data[(cursor_offset.0 + cursor_offset.1 * texture_width) as usize ..];
-            let source = std::mem::transmute(bitmap.buffer);
-            let source = std::slice::from_raw_parts(source, destination.len());
+    let blend = glium::Blend {
+        color: blending_function,
+        alpha: blending_function,
+        constant_value: (1.0, 1.0, 1.0, 1.0),
+    };
 
-            for y in 0 .. bitmap.rows as u32 {
-                let source = &source[(y * bitmap.width as u32) as usize ..];
-                let destination = &mut destination[(y * texture_width) as usize ..];
+    DrawParameters {
+        blend: blend,
+        .. Default::default()
+    }
+}
 
-                for x in 0 .. bitmap.width {
-                    // the values in source are bytes between 0 and 255, but we want floats between 0 and 1
-                    let val: u8 = *source.get(x as usize).unwrap();
-                    let val = (val as f32) / (std::u8::MAX as f32);
-                    let dest = destination.get_mut(x as usize).unwrap();
-                    *dest = val;
-                }
-            }
+// Loads and renders the glyph of a character in the glyph slot of the face, emboldening
+// or slanting it as `synthesis` says. Returns false if that failed.
+unsafe fn load_glyph(face: freetype::FT_Face, character: char, synthesis: FontStyle, font_size: u32) -> bool {
+    let index = freetype::FT_Get_Char_Index(face, character as freetype::FT_ULong);
+    if synthesis == FontStyle::Regular {
+        return freetype::FT_Load_Glyph(face, index, freetype::FT_LOAD_RENDER) == 0;
+    }
 
-            cursor_offset.0 += bitmap.width as u32;
-            debug_assert!(cursor_offset.0 <= texture_width);
+    if freetype::FT_Load_Glyph(face, index, freetype::FT_LOAD_DEFAULT) != 0 {
+        return false;
+    }
+    let glyph = (*face).glyph;
+    if (*glyph).format == freetype::FT_GLYPH_FORMAT_OUTLINE {
+        let outline = &mut (*glyph).outline;
+        if synthesis.is_italic() {
+            // slant by about 12 degrees, like FreeType does
+            let shear = freetype::FT_Matrix { xx: 0x10000, xy: 0x0366a, yx: 0, yy: 0x10000 };
+            freetype::FT_Outline_Transform(outline, &shear);
         }
+        if synthesis.is_bold() {
+            // thicken the strokes by 1/24 em, in 26.6 fixed point
+            let strength = (font_size * 64 / 24) as freetype::FT_Pos;
+            freetype::FT_Outline_Embolden(outline, strength);
+        }
+    }
+    freetype::FT_Render_Glyph(glyph, freetype::FT_RENDER_MODE_NORMAL) == 0
+}
 
-        // filling infos about that character
-        // tex_size and tex_coords are in pixels for the moment ; they will be divided
-        // by the texture dimensions later
-        let left_padding = (*(*face).glyph).bitmap_left;
-
-        Some((character, CharacterInfos {
-            tex_size: (bitmap.width as f32, bitmap.rows as f32),
-            tex_coords: (offset_x_before_copy as f32, cursor_offset.1 as f32),
-            size: (bitmap.width as f32, bitmap.rows as f32),
-            left_padding: left_padding as f32,
-            right_padding: ((*(*face).glyph).advance.x as i32 - bitmap.width * 64 - left_padding * 64) as f32 / 64.0,
-            height_over_line: (*(*face).glyph).bitmap_top as f32,
-        }))
-    }).collect();
+// Rasterises the glyph of a character, emboldening or slanting it as `synthesis` says.
+// Returns `None` if that failed.
+unsafe fn rasterize(face: freetype::FT_Face, character: char, synthesis: FontStyle, font_size: u32) -> Option<Bitmap> {
+    if !load_glyph(face, character, synthesis, font_size) {
+        return None;
+    }
+    let glyph = (*face).glyph;
+    let bitmap = &(*glyph).bitmap;
+    let (width, rows, pitch) = (bitmap.width as u32, bitmap.rows as u32, bitmap.pitch.abs() as usize);
+
+    let mut data = Vec::with_capacity((width * rows) as usize);
+    if width > 0 && rows > 0 {
+        let source = std::slice::from_raw_parts(bitmap.buffer as *const u8, pitch * rows as usize);
+        for row in source.chunks(pitch) {
+            // the values in source are bytes between 0 and 255, but we want floats between 0 and 1
+            data.extend(row[.. width as usize].iter().map(|&val| val as f32 / std::u8::MAX as f32));
+        }
+    }
 
-    // adding blank lines at the end until the height of the texture is a power of two
-    {
-        let current_height = texture_data.len() as u32 / texture_width;
-        let requested_height = get_nearest_po2(current_height);
-        texture_data.extend(iter::repeat(0.0).take((texture_width * (requested_height - current_height)) as usize));
-    }
-
-    // now our texture is finished
-    // we know its final dimensions, so we can divide all the pixels values into (0,1) range
-    assert!((texture_data.len() as u32 % texture_width) == 0);
-    let texture_height = (texture_data.len() as u32 / texture_width) as f32;
-    let float_texture_width = texture_width as f32;
-    for chr in characters_infos.iter_mut() {
-        chr.1.tex_size.0 /= float_texture_width;
-        chr.1.tex_size.1 /= texture_height;
-        chr.1.tex_coords.0 /= float_texture_width;
-        chr.1.tex_coords.1 /= texture_height;
-        chr.1.size.0 /= em_pixels as f32;
-        chr.1.size.1 /= em_pixels as f32;
-        chr.1.left_padding /= em_pixels as f32;
-        chr.1.right_padding /= em_pixels as f32;
-        chr.1.height_over_line /= em_pixels as f32;
-    }
-
-    // returning
-    (TextureData {
-        data: texture_data,
-        width: texture_width,
-        height: texture_height as u32,
-    }, characters_infos, em_pixels)
+    Some(Bitmap {
+        width: width,
+        rows: rows,
+        data: data,
+        left: (*glyph).bitmap_left,
+        top: (*glyph).bitmap_top,
+        advance: (*glyph).advance.x as f32 / 64.0,
+    })
 }
 
 /// Function that will calculate the nearest power of two.
@@ -630,3 +1384,76 @@ fn get_nearest_po2(mut x: u32) -> u32 {
     x = x | (x >> 16);
     x + 1
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // allocates a glyph of 100x100 pixels, four of which fill a page of 256 pixels
+    fn allocate_large(atlas: &mut Atlas) -> (usize, PageChange) {
+        let (page, _, change) = atlas.allocate(100, 100).unwrap();
+        (page, change)
+    }
+
+    #[test]
+    fn shelf_reuse() {
+        let mut atlas = Atlas::new(256);
+        assert_eq!(atlas.allocate(10, 10), Some((0, (2, 2), PageChange::Added)));
+        assert_eq!(atlas.allocate(10, 10), Some((0, (14, 2), PageChange::Kept)));
+        // too high for the first shelf
+        assert_eq!(atlas.allocate(10, 20), Some((0, (2, 18), PageChange::Kept)));
+        // back on the lowest shelf high enough
+        assert_eq!(atlas.allocate(10, 6), Some((0, (26, 2), PageChange::Kept)));
+        assert_eq!(atlas.pages[0].shelves.len(), 2);
+    }
+
+    #[test]
+    fn full_page() {
+        let mut atlas = Atlas::new(256);
+        for _ in 0..4 {
+            assert_eq!(allocate_large(&mut atlas).0, 0);
+        }
+        assert_eq!(allocate_large(&mut atlas), (1, PageChange::Added));
+        // a small glyph still fits in the first page
+        assert_eq!(atlas.allocate(10, 10).map(|(page, _, _)| page), Some(0));
+    }
+
+    #[test]
+    fn glyph_larger_than_page() {
+        let mut atlas = Atlas::new(256);
+        assert_eq!(atlas.allocate(300, 10), None);
+        assert_eq!(atlas.allocate(10, 300), None);
+        assert!(atlas.pages.is_empty());
+    }
+
+    #[test]
+    fn pages_used_this_frame_are_kept() {
+        let mut atlas = Atlas::new(256);
+        for _ in 0..4 * MAX_PAGES {
+            allocate_large(&mut atlas);
+        }
+        assert_eq!(atlas.pages.len(), MAX_PAGES);
+        // all the pages are used by the frame being drawn
+        assert_eq!(allocate_large(&mut atlas), (MAX_PAGES, PageChange::Added));
+
+        atlas.frame += 1;
+        for page in 0..MAX_PAGES + 1 {
+            if page != 2 {
+                atlas.touch(page);
+            }
+        }
+        let epoch = atlas.pages[2].epoch;
+        assert_eq!(allocate_large(&mut atlas), (MAX_PAGES, PageChange::Kept));
+        for _ in 0..2 {
+            allocate_large(&mut atlas);
+        }
+        assert_eq!(allocate_large(&mut atlas), (2, PageChange::Emptied));
+        assert_eq!(atlas.pages[2].epoch, epoch + 1);
+        // every page is used now
+        assert_eq!(allocate_large(&mut atlas).1, PageChange::Kept);
+        for _ in 0..2 {
+            allocate_large(&mut atlas);
+        }
+        assert_eq!(allocate_large(&mut atlas), (MAX_PAGES + 1, PageChange::Added));
+    }
+}
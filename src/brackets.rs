@@ -0,0 +1,147 @@
+// Bracket matching on the cached lines.
+//
+// The core does not report bracket pairs, so the frontend scans the lines it has in its
+// cache. The scan gives up at the first line which is not cached, so pairs spanning more
+// than the visible region are not found. Brackets in strings and comments are counted
+// like any other.
+
+/// A position in the buffer as (line, column).
+pub type Pos = (u64, u64);
+
+const PAIRS: [(char, char); 3] = [('(', ')'), ('[', ']'), ('{', '}')];
+
+fn opening(c: char) -> Option<char> {
+    PAIRS.iter().find(|&&(_, close)| close == c).map(|&(open, _)| open)
+}
+
+fn closing(c: char) -> Option<char> {
+    PAIRS.iter().find(|&&(open, _)| open == c).map(|&(_, close)| close)
+}
+
+/// Return the bracket pair adjacent to the cursor at `pos`, or else the innermost pair
+/// enclosing it. `get_line` returns the text of a line, or `None` if it is not known.
+pub fn find_pair<'t, F>(get_line: F, pos: Pos) -> Option<(Pos, Pos)>
+    where F: Fn(u64) -> Option<&'t str>
+{
+    let (line, column) = pos;
+    let text = match get_line(line) { Some(text) => text, None => return None };
+    let column = column as usize;
+    if column > text.len() || !text.is_char_boundary(column) {
+        return None;
+    }
+
+    // a bracket right after the cursor, then a bracket right before it
+    let after = text[column..].chars().next().map(|c| (column, c));
+    let before = text[..column].char_indices().next_back();
+    for &(i, c) in after.iter().chain(before.iter()) {
+        let start = (line, i as u64);
+        if let Some(close) = closing(c) {
+            if let Some(end) = scan_forward(&get_line, (line, (i + c.len_utf8()) as u64), c, close) {
+                return Some((start, end));
+            }
+        } else if let Some(open) = opening(c) {
+            if let Some(end) = scan_backward(&get_line, start, open, c) {
+                return Some((end, start));
+            }
+        }
+    }
+
+    // the innermost enclosing pair of any kind
+    PAIRS.iter().filter_map(|&(open, close)| {
+        scan_backward(&get_line, pos, open, close).and_then(|start| {
+            scan_forward(&get_line, pos, open, close).map(|end| (start, end))
+        })
+    }).max_by_key(|&(start, _)| start)
+}
+
+/// Find the unmatched `close` bracket at or after `pos`.
+fn scan_forward<'t, F>(get_line: &F, pos: Pos, open: char, close: char) -> Option<Pos>
+    where F: Fn(u64) -> Option<&'t str>
+{
+    let mut depth = 0;
+    let mut line = pos.0;
+    let mut from = pos.1 as usize;
+    while let Some(text) = get_line(line) {
+        for (i, c) in text[from..].char_indices() {
+            if c == open {
+                depth += 1;
+            } else if c == close {
+                if depth == 0 {
+                    return Some((line, (from + i) as u64));
+                }
+                depth -= 1;
+            }
+        }
+        line += 1;
+        from = 0;
+    }
+    None
+}
+
+/// Find the unmatched `open` bracket before `pos`.
+fn scan_backward<'t, F>(get_line: &F, pos: Pos, open: char, close: char) -> Option<Pos>
+    where F: Fn(u64) -> Option<&'t str>
+{
+    let mut depth = 0;
+    let mut line = pos.0;
+    let mut to = Some(pos.1 as usize);
+    while let Some(text) = get_line(line) {
+        let to_column = to.unwrap_or(text.len());
+        for (i, c) in text[..to_column].char_indices().rev() {
+            if c == close {
+                depth += 1;
+            } else if c == open {
+                if depth == 0 {
+                    return Some((line, i as u64));
+                }
+                depth -= 1;
+            }
+        }
+        if line == 0 {
+            break;
+        }
+        line -= 1;
+        to = None;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(lines: &[&str], pos: Pos) -> Option<(Pos, Pos)> {
+        find_pair(|line| lines.get(line as usize).map(|s| *s), pos)
+    }
+
+    #[test]
+    fn nested_pairs() {
+        let lines = ["f(a(b)c)"];
+        assert_eq!(pair(&lines, (0, 1)), Some(((0, 1), (0, 7))));
+        assert_eq!(pair(&lines, (0, 3)), Some(((0, 3), (0, 5))));
+        // inside the inner pair, the innermost enclosing pair is found
+        assert_eq!(pair(&lines, (0, 5)), Some(((0, 3), (0, 5))));
+        assert_eq!(pair(&lines, (0, 7)), Some(((0, 1), (0, 7))));
+    }
+
+    #[test]
+    fn pair_spanning_lines() {
+        let lines = ["fn f() {", "    g(x)", "}"];
+        assert_eq!(pair(&lines, (0, 7)), Some(((0, 7), (2, 0))));
+        assert_eq!(pair(&lines, (2, 0)), Some(((0, 7), (2, 0))));
+        assert_eq!(pair(&lines, (1, 2)), Some(((0, 7), (2, 0))));
+    }
+
+    #[test]
+    fn unmatched_bracket() {
+        assert_eq!(pair(&["(a"], (0, 0)), None);
+        assert_eq!(pair(&["a]"], (0, 1)), None);
+        assert_eq!(pair(&["a b"], (0, 1)), None);
+    }
+
+    #[test]
+    fn cursor_after_closing_bracket() {
+        assert_eq!(pair(&["x(a) y"], (0, 4)), Some(((0, 1), (0, 3))));
+        assert_eq!(pair(&["[a]"], (0, 3)), Some(((0, 0), (0, 2))));
+    }
+}
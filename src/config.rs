@@ -0,0 +1,106 @@
+use std::env;
+
+use cursor::CursorShape;
+
+/// User configuration. Every setting can be overridden by an environment variable,
+/// in the same way as the core path is given by `xicore`.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub cursor_shape: CursorShape, // xi_cursor = block | bar | underline
+    pub cursor_blink: bool,        // xi_cursor_blink = 0 | 1
+    pub tab_width: u32,            // xi_tab_width = <spaces>
+    pub show_tabs: bool,           // xi_show_tabs = 0 | 1
+    pub show_whitespace: bool,     // xi_show_whitespace = 0 | 1
+    pub rulers: Vec<u32>,          // xi_rulers = <column>,<column>,...
+    pub line_limit: Option<u32>,   // xi_line_limit = <column>
+    pub minimap: bool,             // xi_minimap = 0 | 1
+    pub diff_tool: String,         // xi_diff_tool = <command>
+    pub autosave_interval: u64,    // xi_autosave_interval = <seconds>
+    pub autosave_on_focus_loss: bool, // xi_autosave_on_focus_loss = 0 | 1
+    pub font: String,              // xi_font = <family> | <path>
+    pub font_fallback: Vec<String>, // xi_font_fallback = <family> | <path>,..., found by fontconfig by default
+    pub font_size: u32,            // xi_font_size = <pixels>
+    pub scale: Option<f32>,        // xi_scale = <factor>, given by the DPI of the screen by default
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            cursor_shape: CursorShape::Bar,
+            cursor_blink: true,
+            tab_width: 4,
+            show_tabs: false,
+            show_whitespace: false,
+            rulers: vec![],
+            line_limit: None,
+            minimap: true,
+            diff_tool: "meld".into(),
+            autosave_interval: 30,
+            autosave_on_focus_loss: false,
+            font: "Hack".into(),
+            font_fallback: vec![],
+            font_size: 15,
+            scale: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Config {
+        let mut config = Config::default();
+        if let Some(shape) = env::var("xi_cursor").ok().and_then(|s| CursorShape::from_name(&s)) {
+            config.cursor_shape = shape;
+        }
+        if let Some(blink) = env_flag("xi_cursor_blink") {
+            config.cursor_blink = blink;
+        }
+        if let Some(tab_width) = env::var("xi_tab_width").ok().and_then(|s| s.parse().ok()).filter(|&width| width > 0) {
+            config.tab_width = tab_width;
+        }
+        if let Some(show_tabs) = env_flag("xi_show_tabs") {
+            config.show_tabs = show_tabs;
+        }
+        if let Some(show_whitespace) = env_flag("xi_show_whitespace") {
+            config.show_whitespace = show_whitespace;
+        }
+        if let Ok(rulers) = env::var("xi_rulers") {
+            config.rulers = rulers.split(',').filter_map(|s| s.trim().parse().ok()).collect();
+        }
+        if let Some(line_limit) = env::var("xi_line_limit").ok().and_then(|s| s.parse().ok()) {
+            config.line_limit = Some(line_limit);
+        }
+        if let Some(minimap) = env_flag("xi_minimap") {
+            config.minimap = minimap;
+        }
+        if let Ok(diff_tool) = env::var("xi_diff_tool") {
+            config.diff_tool = diff_tool;
+        }
+        if let Some(interval) = env::var("xi_autosave_interval").ok().and_then(|s| s.parse().ok()) {
+            config.autosave_interval = interval;
+        }
+        if let Some(autosave) = env_flag("xi_autosave_on_focus_loss") {
+            config.autosave_on_focus_loss = autosave;
+        }
+        if let Ok(font) = env::var("xi_font") {
+            config.font = font;
+        }
+        if let Ok(fallback) = env::var("xi_font_fallback") {
+            config.font_fallback = fallback.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect();
+        }
+        if let Some(font_size) = env::var("xi_font_size").ok().and_then(|s| s.parse().ok()).filter(|&size| size > 0) {
+            config.font_size = font_size;
+        }
+        if let Some(scale) = env::var("xi_scale").ok().and_then(|s| s.parse().ok()).filter(|&scale: &f32| scale > 0.) {
+            config.scale = Some(scale);
+        }
+        config
+    }
+}
+
+fn env_flag(name: &str) -> Option<bool> {
+    match env::var(name).as_ref().map(|s| s.as_str()) {
+        Ok("1") | Ok("true") | Ok("yes") => Some(true),
+        Ok("0") | Ok("false") | Ok("no") => Some(false),
+        _ => None,
+    }
+}
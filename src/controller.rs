@@ -1,15 +1,22 @@
 
-use std::time::Duration;
-use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{cmp, env, fs, io, process};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 use glium::backend::glutin_backend::GlutinFacade;
 use serde_json::Value;
 use clipboard::ClipboardContext;
 
-use core::Core;
-use renderer::Renderer;
+use core::{self, Core};
+use renderer::{Renderer, Target};
 use text::Text;
+use status_bar::StatusBar;
 use file_dialog;
+use recovery;
+use watcher::{Change, Watcher};
+use waker::{Timer, Waker};
+use config::Config;
 
 // pub struct Controller {
 //     core: Core,
@@ -19,30 +26,121 @@ use file_dialog;
 //     state: State,
 // }
 
-pub struct State<'a> {
-    pub filename: Option<String>,
-    pub text: Text<'a>,
+pub struct State {
+    pub filename: Option<String>, // set with `set_filename`
+    directory: Option<String>, // of the file, as shown in the title
+    pub text: Text,
+    pub status_bar: StatusBar,
     pub first_line: u64,
     pub line_count: u64,
     pub scroll_to: (u64, u64),
+    pub pristine: bool,
+    pub autosaved: bool, // modified, but saved to a recovery file, which the core counts as pristine
+    pub saved_at: Option<Instant>,
+    pub wrapped: bool, // F1 asks the core to wrap the lines, which it does not undo
 }
 
-impl<'a> State<'a> {
-    pub fn new(filename: Option<String>, renderer: &'a Renderer) -> State<'a> {
+impl State {
+    pub fn new(filename: Option<String>, renderer: &Renderer) -> State {
         State {
+            directory: filename.as_ref().and_then(|f| title_directory(f)),
             filename: filename,
             text: Text::new(&renderer),
+            status_bar: StatusBar::new(&renderer),
             first_line: 0,
             line_count: 1,
             scroll_to: (0, 0),
+            pristine: true,
+            autosaved: false,
+            saved_at: None,
+            wrapped: false,
         }
     }
 
+    /// Change the file of the buffer, after it is opened or saved under another name.
+    pub fn set_filename(&mut self, filename: String) {
+        self.directory = title_directory(&filename);
+        self.filename = Some(filename);
+    }
+
+    /// Return the file name of the buffer without the directory.
+    pub fn name(&self) -> String {
+        self.filename.as_ref()
+            .map(|f| Path::new(f).file_name().map_or(f.clone(), |n| n.to_string_lossy().into_owned()))
+            .unwrap_or("untitled".into())
+    }
+
+    /// Return true if the buffer has changes which are not saved to its file.
+    pub fn is_modified(&self) -> bool {
+        !self.pristine || self.autosaved
+    }
+
+    /// Forget the unsaved changes, which are about to be replaced, and their recovery file.
+    pub fn discard_changes(&mut self) {
+        recovery::remove(self.filename.as_ref().map(|f| f.as_str()));
+        self.pristine = true;
+        self.autosaved = false;
+    }
+
+    /// Record that the buffer was just saved.
+    pub fn mark_saved(&mut self) {
+        self.discard_changes();
+        self.saved_at = Some(Instant::now());
+    }
+
+    /// Return true if the buffer was saved so recently that a change of the file on disk
+    /// is probably caused by the save.
+    pub fn saved_recently(&self) -> bool {
+        self.saved_at.map_or(false, |t| t.elapsed() < Duration::from_millis(SAVE_GRACE_MS))
+    }
+
+    /// Update the status bar text from the current state.
+    pub fn update_status_bar(&mut self, renderer: &Renderer) {
+        let mut text = format!("{}{}", self.name(), if self.is_modified() { " [+]" } else { "" });
+        if let Some((line, column)) = self.text.cursor_display_position() {
+            text.push_str(&format!("   Ln {}, Col {}", line, column));
+        }
+        // the core reads and writes files as UTF-8 only
+        text.push_str(&format!("   {} lines   UTF-8   {}   {}", self.line_count, self.text.line_ending(),
+                               if self.wrapped { "Wrap" } else { "No wrap" }));
+        if let Some(message) = self.status_bar.message() {
+            text.push_str("   ");
+            text.push_str(message);
+        }
+        self.status_bar.set_text(renderer, text);
+    }
+
+    /// Lay out the text and the status bar again after the font of the renderer changed.
+    pub fn relayout(&mut self, renderer: &Renderer) {
+        self.text.relayout(renderer);
+        self.status_bar.relayout(renderer);
+    }
+
+    /// Draw the text and the status bar.
+    pub fn render(&mut self, renderer: &Renderer, target: &mut Target) {
+        self.update_status_bar(renderer);
+        self.text.render(target);
+        self.status_bar.render(target);
+    }
+
+    /// Return the window title, e.g. "● README.md — ~/proj — xi_glium". The dot marks
+    /// a buffer with unsaved changes.
+    pub fn title(&self) -> String {
+        let mut title = if self.is_modified() { "● ".into() } else { String::new() };
+        title.push_str(&self.name());
+        if let Some(ref directory) = self.directory {
+            title.push_str(" — ");
+            title.push_str(directory);
+        }
+        title.push_str(" — xi_glium");
+        title
+    }
+
     // the 'params' field is specified in
     // https://github.com/google/xi-editor/blob/master/doc/frontend.md#settext
     // The line data itself is updated in fn update_lines
     // renderer is needed, because the new lines are rendered as they come.
-    pub fn update(&mut self, renderer: &'a Renderer, params: Value) {
+    pub fn update(&mut self, renderer: &Renderer, params: Value) {
         // println!("{:?}", params);
         let dict = params.as_object().unwrap().get("update").unwrap().as_object().unwrap();
 
@@ -50,6 +148,9 @@ impl<'a> State<'a> {
         self.line_count = dict.get("height").unwrap().as_u64().unwrap();
         self.text.refresh(self.line_count);
         self.text.add_lines(&renderer, dict.get("lines").unwrap(), self.first_line);
+        if let Some(pristine) = dict.get("pristine").and_then(|x| x.as_bool()) {
+            self.pristine = pristine;
+        }
         // TODO: is this supposed to be in every message, or not?
         if let Some(x) = dict.get("scrollto")
                              .and_then(|x| x.as_array()) {
@@ -58,55 +159,157 @@ impl<'a> State<'a> {
     }
 }
 
+/// Return the absolute directory of a file as shown in the window title, with the home
+/// directory abbreviated to "~", or `None` if it does not exist.
+fn title_directory(filename: &str) -> Option<String> {
+    let dir = match Path::new(filename).parent() {
+        Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+        Some(dir) => dir,
+        None => Path::new("/"),
+    };
+    let dir = dir.canonicalize().ok()?.to_string_lossy().into_owned();
+    let home = env::var("HOME").unwrap_or_default();
+    if !home.is_empty() && (dir == home || dir.starts_with(&(home.clone() + "/"))) {
+        Some(format!("~{}", &dir[home.len()..]))
+    } else {
+        Some(dir)
+    }
+}
+
+// Changes of the file on disk up to this long after saving are ignored.
+const SAVE_GRACE_MS: u64 = 1000;
+
+// The range of font sizes in pixels for zooming.
+const MIN_FONT_SIZE: u32 = 6;
+const MAX_FONT_SIZE: u32 = 72;
+
+// Two clicks closer than this in time and space are counted as a multi-click.
+const MULTI_CLICK_TIME_MS: u64 = 500;
+const MULTI_CLICK_DISTANCE: i32 = 4;
+
 #[derive(Debug)]
 struct MouseState {
     pub line: u64,
     pub column: u64,
+    pub x: i32,
+    pub y: i32,
     pub pressed: bool,
+    pub column_anchor: Option<(u64, i32)>, // (line, x) where an alt-drag column selection started
+    pub last_click: Option<(Instant, i32, i32)>, // (time, x, y) of the last button press
+    pub click_count: u64,
+    pub minimap_drag: bool,
 }
 
-pub fn run(core_path: &str, filename: Option<String>, display: GlutinFacade) {
-    let mut core = Core::new(&core_path);
+impl MouseState {
+    /// Register a button press at the current position and time and return the click count:
+    /// 1 for a single click, 2 for a double-click and 3 for a triple-click.
+    /// A fourth quick click starts over with a single click.
+    fn register_click(&mut self, now: Instant) -> u64 {
+        let repeated = match self.last_click {
+            Some((time, x, y)) => {
+                now.duration_since(time) < Duration::from_millis(MULTI_CLICK_TIME_MS)
+                    && (x - self.x).abs() <= MULTI_CLICK_DISTANCE
+                    && (y - self.y).abs() <= MULTI_CLICK_DISTANCE
+            }, None => false,
+        };
+        self.click_count = if repeated && self.click_count < 3 { self.click_count + 1 } else { 1 };
+        self.last_click = Some((now, self.x, self.y));
+        self.click_count
+    }
+}
+
+/// An action which discards the buffer, waiting for the user to decide what to do with
+/// unsaved changes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Pending {
+    Close,
+    Open,
+}
+
+pub fn run(core_path: &str, filename: Option<String>, display: GlutinFacade, config: Config) {
+    let waker = Waker::new(display.get_window().map(|window| window.create_window_proxy()));
+    let timer = Timer::new(waker.clone());
+    let mut core = Core::new(&core_path, waker.clone());
 
     if let Some(ref filename) = filename {
         core.open(filename);
     }
 
-    let renderer = Renderer::new(display.clone());
+    let scale = window_scale(&display, &config);
+    let mut renderer = Renderer::new(display.clone(), config, scale);
     let mut state = State::new(filename, &renderer);
     let mut clipboard = ClipboardContext::new().unwrap();
 
     // the main loop
     // TODO: replace stateful ctrl/shift modifiers by stateless ones
-    let (mut ctrl, mut shift) = (false, false);
+    let (mut ctrl, mut shift, mut alt) = (false, false, false);
     let (mut file_open_rx, mut file_save_rx) = (None, None); // The receiver of a file dialog.
-    let mut mouse = MouseState { line: 0, column: 0, pressed: false };
+    let mut confirm_rx = None; // The receiver of the save / discard / cancel dialog.
+    let mut pending = None; // The action to run once the unsaved changes are saved or discarded.
+    let mut reload_rx = None; // The receiver of the reload / keep / diff dialog.
+    let mut watcher: Option<(String, Watcher)> = None; // The watched file name and its watcher.
+    let mut restore_rx = recovery::find(state.filename.as_ref().map(|f| f.as_str()))
+        .map(|path| (path, file_dialog::confirm_restore(&state.name(), waker.clone()))); // A recovery file and the receiver of its dialog.
+    let mut last_autosave = Instant::now();
+    let mut minimap_request = None; // The ID of the request for minimap lines, their first line and the updates before it.
+    let mut mouse = MouseState {
+        line: 0, column: 0, x: 0, y: 0, pressed: false,
+        column_anchor: None, last_click: None, click_count: 0, minimap_drag: false,
+    };
     let mut window_height = 0;
+    let mut title = String::new();
+    let mut events = vec![];
+    let mut dirty = true; // whether the window needs to be redrawn
     'a: loop {
         while let Ok(value) = core.update_rx.try_recv() {
             state.update(&renderer, value);
+            dirty = true;
+        }
+        while let Ok(message) = core.alert_rx.try_recv() {
+            state.status_bar.set_message(message);
+            dirty = true;
+        }
+        if let Some((id, first, updates)) = minimap_request {
+            if let Some(lines) = core.response(id) {
+                state.text.add_minimap_lines(&lines, first, updates);
+                minimap_request = None;
+                dirty = true;
+            }
         }
 
-        // polling and handling the events received by the window
-        for event in display.poll_events() {
+        // handling the events received by the window
+        for event in events.drain(..) {
             use glium::glutin::*;
+            match event {
+                Event::MouseMoved(..) => (), // redrawn when the core answers, if at all
+                _ => dirty = true, // including `Awakened`, which comes with data for the loop or a due timer
+            }
+            if let Event::KeyboardInput(ElementState::Pressed, _, _) = event {
+                state.text.cursor.reset();
+            }
             match event {
                 Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::O)) => {
-                    if ctrl && file_open_rx.is_none() {
-                        file_open_rx = Some(file_dialog::open());
+                    if ctrl && file_open_rx.is_none() && confirm_rx.is_none() {
+                        if !state.is_modified() {
+                            file_open_rx = Some(file_dialog::open(waker.clone()));
+                        } else {
+                            pending = Some(Pending::Open);
+                            confirm_rx = Some(file_dialog::confirm_unsaved(&state.name(), "opening another file", waker.clone()));
+                        }
                         ctrl = false; // ctrl is typically released over the dialog
                     }
                 }, Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::S)) => {
                     if ctrl {
                         if let Some(ref filename) = state.filename {
                             core.save(filename);
+                            state.mark_saved();
                         } else {
-                            file_save_rx = Some(file_dialog::save());
+                            file_save_rx = Some(file_dialog::save(waker.clone()));
                             ctrl = false;
                         }
 
                         if shift {
-                            file_save_rx = Some(file_dialog::save());
+                            file_save_rx = Some(file_dialog::save(waker.clone()));
                             ctrl = false;
                             shift = false;
                         }
@@ -133,6 +336,44 @@ pub fn run(core_path: &str, filename: Option<String>, display: GlutinFacade) {
                         let s = clipboard.get_contents().unwrap();
                         core.paste(s);
                     }
+                }, Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::D)) => {
+                    if ctrl {
+                        core.add_next_occurrence();
+                    }
+                }, Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::M)) => {
+                    if ctrl {
+                        if let Some((line, column)) = state.text.matching_bracket_target() {
+                            core.click(line, column, 0, 1);
+                        }
+                    }
+                }, Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::LBracket)) => {
+                    if ctrl && shift && state.text.fold_at_cursor() {
+                        let (first, last) = state.text.scroll_range();
+                        core.scroll(first, last);
+                    }
+                }, Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::RBracket)) => {
+                    if ctrl && shift && state.text.unfold_at_cursor() {
+                        let (first, last) = state.text.scroll_range();
+                        core.scroll(first, last);
+                    }
+                }, Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Equals))
+                 | Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Add)) => {
+                    if ctrl {
+                        let size = renderer.font_size() + 1;
+                        zoom(&mut renderer, &mut state, &mut core, size);
+                    }
+                }, Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Minus))
+                 | Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Subtract)) => {
+                    if ctrl {
+                        let size = renderer.font_size() - 1;
+                        zoom(&mut renderer, &mut state, &mut core, size);
+                    }
+                }, Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Key0))
+                 | Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Numpad0)) => {
+                    if ctrl {
+                        let size = renderer.config.font_size;
+                        zoom(&mut renderer, &mut state, &mut core, size);
+                    }
                 }, Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Left)) => {
                     if shift { core.left_sel() } else { core.left() };
                 }, Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Right)) => {
@@ -152,8 +393,19 @@ pub fn run(core_path: &str, filename: Option<String>, display: GlutinFacade) {
                     core.insert_newline();
                 }, Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::F1)) => {
                     core.f1();
+                    state.wrapped = true;
                 }, Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::F2)) => {
                     core.f2();
+                }, Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::F3)) => {
+                    state.text.show_whitespace = !state.text.show_whitespace;
+                }, Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::F12)) => {
+                    let (w, h) = display.get_framebuffer_dimensions();
+                    let path = snapshot_path();
+                    let message = match save_snapshot(&mut state, &renderer, (w, h), &path) {
+                        Ok(()) => format!("Saved a snapshot to {}", path.display()),
+                        Err(e) => format!("Could not save the snapshot: {}", e),
+                    };
+                    state.status_bar.set_message(message);
 
                 }, Event::KeyboardInput(state, _, Some(VirtualKeyCode::LControl))
                  | Event::KeyboardInput(state, _, Some(VirtualKeyCode::RControl)) => {
@@ -163,6 +415,9 @@ pub fn run(core_path: &str, filename: Option<String>, display: GlutinFacade) {
                  | Event::KeyboardInput(state, _, Some(VirtualKeyCode::RShift)) => {
                     shift = state == ElementState::Pressed;
                     println!("shift: {}", shift);
+                }, Event::KeyboardInput(state, _, Some(VirtualKeyCode::LAlt))
+                 | Event::KeyboardInput(state, _, Some(VirtualKeyCode::RAlt)) => {
+                    alt = state == ElementState::Pressed;
                 },
                 Event::ReceivedCharacter(ch) => {
                     // filter out: delete, backspace, enter, ctrl-modified chars
@@ -174,28 +429,83 @@ pub fn run(core_path: &str, filename: Option<String>, display: GlutinFacade) {
                 },
                 Event::MouseWheel(MouseScrollDelta::LineDelta(_dx,dy), TouchPhase::Moved) => {
                     state.text.scroll((-dy*3.) as f64);
-                    core.scroll(state.text.top as u64, (state.text.top + state.text.height) as u64);
+                    let (first, last) = state.text.scroll_range();
+                    core.scroll(first, last);
                 }, Event::MouseMoved(x, y) => {
                     let (line, column) = state.text.get_line_col(x, window_height - y);
+                    mouse.x = x;
+                    mouse.y = y;
+                    if mouse.minimap_drag {
+                        dirty = true;
+                        state.text.scroll_to_minimap(y);
+                        let (first, last) = state.text.scroll_range();
+                        core.scroll(first, last);
+                        continue;
+                    }
                     if mouse.line != line || mouse.column != column { // update only if needed
                         mouse.line = line;
                         mouse.column = column;
-                        if mouse.pressed {
+                        if let (true, Some(anchor)) = (mouse.pressed, mouse.column_anchor) {
+                            core.select_columns(&state.text.get_column_ranges(anchor, (line, x)));
+                        } else if mouse.pressed {
                             core.drag(line, column);
                         }
                     }
                 },
                 Event::MouseInput(button_state, MouseButton::Left) => {
                     mouse.pressed = button_state == ElementState::Pressed;
-                    if mouse.pressed {
-                        core.click(mouse.line, mouse.column);
+                    mouse.column_anchor = None;
+                    mouse.minimap_drag = false;
+                    if mouse.pressed && state.text.minimap_contains(mouse.x) {
+                        mouse.pressed = false;
+                        mouse.minimap_drag = true;
+                        state.text.scroll_to_minimap(mouse.y);
+                        let (first, last) = state.text.scroll_range();
+                        core.scroll(first, last);
+                    } else if mouse.pressed && state.text.in_gutter(mouse.x) {
+                        mouse.pressed = false;
+                        if state.text.toggle_fold(mouse.line) {
+                            let (first, last) = state.text.scroll_range();
+                            core.scroll(first, last);
+                        }
+                    } else if mouse.pressed {
+                        if alt {
+                            mouse.column_anchor = Some((mouse.line, mouse.x));
+                            core.gesture(mouse.line, mouse.column, "point_select");
+                        } else if ctrl {
+                            core.add_cursor(mouse.line, mouse.column);
+                        } else {
+                            let click_count = mouse.register_click(Instant::now());
+                            let flags = if shift { core::FLAG_SHIFT } else { 0 };
+                            core.click(mouse.line, mouse.column, flags, click_count);
+                        }
                     }
                 },
                 Event::Resized(w, h) => {
+                    rescale(&display, &mut renderer, &mut state, &mut core);
                     window_height = h as i32;
                     state.text.set_size(w, h);
-                    core.scroll(state.text.top as u64, (state.text.top + state.text.height.round()) as u64);
-                }, Event::Closed => break 'a,
+                    let (first, last) = state.text.scroll_range();
+                    core.scroll(first, last);
+                }, Event::Moved(..) => {
+                    // the window may have moved to a screen with another DPI
+                    rescale(&display, &mut renderer, &mut state, &mut core);
+                }, Event::Focused(focused) => {
+                    state.text.cursor.set_focused(focused);
+                    if !focused && renderer.config.autosave_on_focus_loss && state.is_modified() {
+                        if let Some(filename) = state.filename.clone() {
+                            core.save(&filename);
+                            state.mark_saved();
+                        }
+                    }
+                }, Event::Closed => {
+                    if !state.is_modified() {
+                        break 'a;
+                    } else if confirm_rx.is_none() {
+                        pending = Some(Pending::Close);
+                        confirm_rx = Some(file_dialog::confirm_unsaved(&state.name(), "closing", waker.clone()));
+                    }
+                },
                 _ => ()
             }
         }
@@ -205,33 +515,324 @@ pub fn run(core_path: &str, filename: Option<String>, display: GlutinFacade) {
                 Ok(Some(filename)) => {
                     // TODO: replace String by Path or OsString
                     core.open(filename.to_str().unwrap());
-                    state.filename = Some(filename.to_str().unwrap().into());
+                    state.text.reset_folds();
+                    state.set_filename(filename.to_str().unwrap().into());
+                    state.pristine = true;
+                    state.autosaved = false;
                     file_open_rx = None;
+                    if let Some(path) = recovery::find(state.filename.as_ref().map(|f| f.as_str())) {
+                        restore_rx = Some((path, file_dialog::confirm_restore(&state.name(), waker.clone())));
+                    }
                 }, _ => {
                     file_open_rx = Some(rx);
                 }
             }
         }
 
+        let mut run_pending = false;
+        if let Some(rx) = confirm_rx.take() {
+            match rx.try_recv() {
+                Ok(file_dialog::Confirm::Save) => {
+                    if let Some(ref filename) = state.filename {
+                        core.save(filename);
+                        state.mark_saved();
+                        run_pending = true;
+                    } else {
+                        // the pending action runs once the file is saved
+                        file_save_rx = Some(file_dialog::save(waker.clone()));
+                    }
+                }, Ok(file_dialog::Confirm::Discard) => {
+                    state.discard_changes();
+                    run_pending = true;
+                }, Ok(file_dialog::Confirm::Cancel) => {
+                    pending = None;
+                }, _ => {
+                    confirm_rx = Some(rx);
+                }
+            }
+        }
+
         if let Some(rx) = file_save_rx.take() {
             match rx.try_recv() {
                 Ok(Some(filename)) => {
                     // TODO: replace String by Path or OsString
                     core.save(filename.to_str().unwrap());
-                    state.filename = Some(filename.to_str().unwrap().into());
+                    recovery::remove(state.filename.as_ref().map(|f| f.as_str()));
+                    state.set_filename(filename.to_str().unwrap().into());
+                    state.mark_saved();
                     file_save_rx = None;
+                    run_pending = true;
+                }, Ok(None) => {
+                    pending = None;
                 }, _ => {
                     file_save_rx = Some(rx);
                 }
             }
         }
 
-        let mut target = renderer.draw();
+        if watcher.as_ref().map(|w| &w.0) != state.filename.as_ref() {
+            watcher = state.filename.as_ref().map(|f| (f.clone(), Watcher::new(Path::new(f), waker.clone())));
+        }
+        let mut change = None;
+        if let Some((_, ref w)) = watcher {
+            while let Ok(c) = w.rx.try_recv() {
+                change = Some(c);
+            }
+        }
+        match (change, state.filename.clone()) {
+            (Some(Change::Modified), _) if state.saved_recently() => (),
+            (Some(Change::Modified), Some(filename)) => {
+                if !state.is_modified() {
+                    core.open(&filename);
+                    state.text.reset_folds();
+                    state.status_bar.set_message(format!("Reloaded {}", state.name()));
+                } else if reload_rx.is_none() {
+                    reload_rx = Some(file_dialog::confirm_reload(&state.name(), waker.clone()));
+                }
+            }, (Some(Change::Deleted), Some(_)) => {
+                state.status_bar.set_message(format!("{} was deleted on disk", state.name()));
+            }, _ => (),
+        }
+
+        if let Some(rx) = reload_rx.take() {
+            match (rx.try_recv(), state.filename.clone()) {
+                (Ok(file_dialog::Reload::Reload), Some(filename)) => {
+                    core.open(&filename);
+                    state.text.reset_folds();
+                    state.discard_changes();
+                }, (Ok(file_dialog::Reload::Diff), Some(filename)) => {
+                    if let Err(e) = show_diff(&renderer.config.diff_tool, &filename, &core.buffer_text(state.line_count)) {
+                        state.status_bar.set_message(format!("Could not show the differences: {}", e));
+                    }
+                    reload_rx = Some(file_dialog::confirm_reload(&state.name(), waker.clone()));
+                }, (Ok(_), _) => (),
+                (Err(_), _) => {
+                    reload_rx = Some(rx);
+                }
+            }
+        }
+
+        if let Some((path, rx)) = restore_rx.take() {
+            match rx.try_recv() {
+                Ok(true) => {
+                    // take over the recovery file, it may be left over by a process that has exited
+                    let own = recovery::path(state.filename.as_ref().map(|f| f.as_str()));
+                    let path = match own {
+                        Some(ref own) if *own != path && fs::rename(&path, own).is_ok() => own.clone(),
+                        _ => path,
+                    };
+                    core.open(path.to_str().unwrap());
+                    state.text.reset_folds();
+                    state.autosaved = true;
+                    state.status_bar.set_message(format!("Restored unsaved changes to {}", state.name()));
+                }, Ok(false) => {
+                    // `recovery::find` only offers files whose process has exited
+                    let _ = fs::remove_file(&path);
+                }, _ => {
+                    restore_rx = Some((path, rx));
+                }
+            }
+        }
+
+        let interval = renderer.config.autosave_interval;
+        if interval > 0 && !state.pristine && last_autosave.elapsed() >= Duration::from_secs(interval) {
+            match recovery::create(state.filename.as_ref().map(|f| f.as_str())) {
+                Ok(path) => {
+                    core.save(path.to_str().unwrap());
+                    state.autosaved = true;
+                }, Err(e) => {
+                    state.status_bar.set_message(format!("Could not save a recovery file: {}", e));
+                }
+            }
+            last_autosave = Instant::now();
+        }
+
+        if run_pending {
+            match pending.take() {
+                Some(Pending::Close) => break 'a,
+                Some(Pending::Open) => file_open_rx = Some(file_dialog::open(waker.clone())),
+                None => (),
+            }
+        }
+
+        let new_title = state.title();
+        if new_title != title {
+            title = new_title;
+            if let Some(window) = display.get_window() {
+                window.set_title(&title);
+            }
+        }
+
+        if dirty {
+            if minimap_request.is_none() {
+                if let Some((first, last)) = state.text.missing_minimap_range() {
+                    minimap_request = Some((core.render_lines(first, last), first, state.text.updates()));
+                }
+            }
+            let mut target = renderer.draw();
+            state.render(&renderer, &mut target);
+            target.finish();
+            dirty = false;
+        }
+
+        // sleep until there is an event, or something is due
+        let autosave = if interval > 0 && !state.pristine {
+            Some(last_autosave + Duration::from_secs(interval))
+        } else {
+            None
+        };
+        let deadlines = [state.text.cursor.next_blink(), state.status_bar.message_expiry(), autosave];
+        timer.wake_at(deadlines.iter().filter_map(|&d| d).min());
+        events.extend(display.wait_events().next());
+        events.extend(display.poll_events());
+    }
+}
+
+/// Fetch the lines shown in the minimap which are not known or outdated, waiting for them,
+/// for a snapshot. The window requests them without waiting. They are requested apart from
+/// the visible lines, so that they are not laid out with the font.
+fn fetch_minimap_lines(core: &mut Core, text: &mut Text) {
+    if let Some((first, last)) = text.missing_minimap_range() {
+        let lines = core.render_lines_sync(first, last);
+        let updates = text.updates();
+        text.add_minimap_lines(&lines, first, updates);
+    }
+}
+
+/// Change the size of the font, within limits, and lay out the text again.
+fn zoom(renderer: &mut Renderer, state: &mut State, core: &mut Core, font_size: u32) {
+    let font_size = cmp::max(MIN_FONT_SIZE, cmp::min(font_size, MAX_FONT_SIZE));
+    if font_size == renderer.font_size() {
+        return;
+    }
+    renderer.set_font_size(font_size);
+    state.relayout(renderer);
+    let (first, last) = state.text.scroll_range();
+    core.scroll(first, last);
+    state.status_bar.set_message(format!("Font size {}", font_size));
+}
+
+/// Return the scale of the window, given by its DPI unless it is configured.
+fn window_scale(display: &GlutinFacade, config: &Config) -> f32 {
+    config.scale.unwrap_or_else(|| display.get_window().map_or(1., |window| window.hidpi_factor()))
+}
+
+/// Lay out everything again if the scale of the window changed.
+fn rescale(display: &GlutinFacade, renderer: &mut Renderer, state: &mut State, core: &mut Core) {
+    let scale = window_scale(display, &renderer.config);
+    if scale == renderer.scale() {
+        return;
+    }
+    renderer.set_scale(scale);
+    state.relayout(renderer);
+    let (first, last) = state.text.scroll_range();
+    core.scroll(first, last);
+}
+
+/// Render the view of `filename` at the given size without a window and save it as a
+/// PNG file. The display can be headless. The cursor does not blink, so that the same
+/// text always gives the same image.
+pub fn snapshot(core_path: &str, filename: Option<String>, display: GlutinFacade, config: Config,
+                size: (u32, u32), output: &Path) -> io::Result<()> {
+    let mut core = Core::new(&core_path, Waker::new(None));
+    if let Some(ref filename) = filename {
+        core.open(filename);
+    }
+
+    let scale = config.scale.unwrap_or(1.);
+    let renderer = Renderer::new(display, config, scale);
+    let mut state = State::new(filename, &renderer);
+    state.text.cursor.blink = false;
+    state.text.set_size(size.0, size.1);
+    let (first, last) = state.text.scroll_range();
+    core.scroll(first, last);
+
+    // wait for the first update, and then until the core has been quiet for a moment
+    let mut timeout = Duration::from_secs(5);
+    while let Ok(value) = core.update_rx.recv_timeout(timeout) {
+        state.update(&renderer, value);
+        timeout = Duration::from_millis(200);
+    }
+    fetch_minimap_lines(&mut core, &mut state.text);
+    save_snapshot(&mut state, &renderer, size, output)
+}
+
+fn save_snapshot(state: &mut State, renderer: &Renderer, size: (u32, u32), path: &Path) -> io::Result<()> {
+    let mut target = renderer.draw_offscreen(size.0, size.1);
+    state.render(renderer, &mut target);
+    target.read().expect("an offscreen target").save(path)
+}
+
+/// Return a new file name for a snapshot in the current directory.
+fn snapshot_path() -> PathBuf {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let mut path = PathBuf::from(format!("xi_glium-{}.png", time));
+    let mut n = 1;
+    while path.exists() {
+        n += 1;
+        path = PathBuf::from(format!("xi_glium-{}-{}.png", time, n));
+    }
+    path
+}
+
+/// Open the diff tool comparing a file with the text of the buffer, which is written to
+/// a temporary file.
+fn show_diff(tool: &str, filename: &str, text: &str) -> io::Result<()> {
+    let name = Path::new(filename).file_name().map_or("buffer".into(), |n| n.to_string_lossy().into_owned());
+    let buffer_path = env::temp_dir().join(format!("{}.xi_glium-{}", name, process::id()));
+    fs::File::create(&buffer_path)?.write_all(text.as_bytes())?;
+    process::Command::new(tool).arg(filename).arg(&buffer_path).spawn()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mouse() -> MouseState {
+        MouseState {
+            line: 0, column: 0, x: 10, y: 10, pressed: false,
+            column_anchor: None, last_click: None, click_count: 0, minimap_drag: false,
+        }
+    }
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
 
-        state.text.render(&mut target);
+    #[test]
+    fn double_and_triple_clicks() {
+        let mut mouse = mouse();
+        let t = Instant::now();
+        assert_eq!(mouse.register_click(t), 1);
+        assert_eq!(mouse.register_click(t + ms(100)), 2);
+        assert_eq!(mouse.register_click(t + ms(200)), 3);
+        // a fourth quick click starts over
+        assert_eq!(mouse.register_click(t + ms(300)), 1);
+        assert_eq!(mouse.register_click(t + ms(400)), 2);
+    }
 
-        target.finish();
+    #[test]
+    fn slow_clicks() {
+        let mut mouse = mouse();
+        let t = Instant::now();
+        assert_eq!(mouse.register_click(t), 1);
+        assert_eq!(mouse.register_click(t + ms(MULTI_CLICK_TIME_MS)), 1);
+        // the time is counted from the last click
+        assert_eq!(mouse.register_click(t + ms(2 * MULTI_CLICK_TIME_MS - 1)), 2);
+    }
 
-        thread::sleep(Duration::from_millis(15));
+    #[test]
+    fn distant_clicks() {
+        let mut mouse = mouse();
+        let t = Instant::now();
+        assert_eq!(mouse.register_click(t), 1);
+        mouse.x += MULTI_CLICK_DISTANCE;
+        mouse.y -= MULTI_CLICK_DISTANCE;
+        assert_eq!(mouse.register_click(t + ms(100)), 2);
+        mouse.x += MULTI_CLICK_DISTANCE + 1;
+        assert_eq!(mouse.register_click(t + ms(200)), 1);
+        mouse.y += MULTI_CLICK_DISTANCE + 1;
+        assert_eq!(mouse.register_click(t + ms(300)), 1);
     }
 }
@@ -1,4 +1,5 @@
 
+use std::collections::HashMap;
 use std::sync::mpsc;
 use std::thread;
 use std::process::{Stdio,Command,ChildStdin};
@@ -8,22 +9,30 @@ use std::io::prelude::*;
 use serde_json::{self,Value};
 use serde_json::builder::*;
 
+use waker::Waker;
+
 macro_rules! println_err (
     ($($arg:tt)*) => { {
         writeln!(&mut ::std::io::stderr(), $($arg)*).expect("failed printing to stderr");
     } }
 );
 
+/// Modifier flag sent with a click to extend the selection to the clicked position.
+pub const FLAG_SHIFT: u64 = 1 << 1;
+
 pub struct Core {
     stdin: ChildStdin,
     pub update_rx: mpsc::Receiver<Value>,
-    rpc_rx: mpsc::Receiver<(u64,Value)>, // ! A simple piping works only for synchronous calls.
+    pub alert_rx: mpsc::Receiver<String>, // messages for the user
+    rpc_rx: mpsc::Receiver<(u64,Value)>,
+    responses: HashMap<u64, Value>, // the responses to asynchronous requests, not taken yet
     rpc_index: u64,
     tab: String,
 }
 
 impl Core {
-    pub fn new(executable: &str) -> Core {
+    /// Start the core. `waker` is woken up whenever an update, an alert or a response arrives.
+    pub fn new(executable: &str, waker: Waker) -> Core {
         // spawn the core process
         let process = Command::new(executable)
                                 .arg("test-file")
@@ -36,6 +45,7 @@ impl Core {
 
 
         let (update_tx, update_rx) = mpsc::channel();
+        let (alert_tx, alert_rx) = mpsc::channel();
         let (rpc_tx, rpc_rx) = mpsc::channel();
         let stdout = process.stdout.unwrap();
         thread::spawn(move || {
@@ -47,9 +57,15 @@ impl Core {
                         // println!("res: {:?}", result);
                         rpc_tx.send((id.as_u64().unwrap(), result.clone())).unwrap();
                         println!("Sent: {:?}", (id.as_u64().unwrap(), result.clone()));
+                        waker.wake();
                     } else if let (Some(method), Some(params)) = (req.get("method"), req.get("params")) {
                         if method.as_str().unwrap() == "update" {
                             update_tx.send(params.clone()).unwrap();
+                            waker.wake();
+                        } else if method.as_str().unwrap() == "alert" {
+                            let msg = params.as_object().and_then(|p| p.get("msg")).and_then(|m| m.as_str());
+                            alert_tx.send(msg.unwrap_or("").into()).unwrap();
+                            waker.wake();
                         } else {
                             panic!("Unknown method {:?}.", method.as_str().unwrap());
                         }
@@ -72,7 +88,7 @@ impl Core {
 
         let stdin = process.stdin.unwrap();
 
-        let mut core = Core { stdin: stdin, update_rx: update_rx, rpc_rx: rpc_rx, rpc_index: 0, tab: "".into() };
+        let mut core = Core { stdin: stdin, update_rx: update_rx, alert_rx: alert_rx, rpc_rx: rpc_rx, responses: HashMap::new(), rpc_index: 0, tab: "".into() };
         core.tab = core.call_sync("new_tab", ArrayBuilder::new().build()).as_str().map(|s|s.into()).unwrap();
         core
     }
@@ -109,9 +125,21 @@ impl Core {
 
     fn call_sync(&mut self, method: &str, params: Value) -> Value {
         let i = self.request(method, params);
-        let (id,result) = self.rpc_rx.recv().unwrap();
-        assert_eq!(i, id);
-        result
+        loop {
+            let (id, result) = self.rpc_rx.recv().unwrap();
+            if id == i {
+                return result;
+            }
+            self.responses.insert(id, result);
+        }
+    }
+
+    /// Return the response to an asynchronous request, if it arrived.
+    pub fn response(&mut self, id: u64) -> Option<Value> {
+        while let Ok((id, result)) = self.rpc_rx.try_recv() {
+            self.responses.insert(id, result);
+        }
+        self.responses.remove(&id)
     }
 
     fn call_edit(&mut self, method: &str, params: Option<Value>) {
@@ -130,6 +158,14 @@ impl Core {
         self.call_sync("edit", obj.build())
     }
 
+    fn call_edit_async(&mut self, method: &str, params: Option<Value>) -> u64 {
+        let obj = ObjectBuilder::new()
+            .insert("method", method)
+            .insert("tab", &self.tab)
+            .insert("params", params.unwrap_or(ArrayBuilder::new().build()));
+        self.request("edit", obj.build())
+    }
+
     pub fn save(&mut self, filename: &str) {
         self.call_edit("save", Some(ObjectBuilder::new().insert("filename", filename).build()));
     }
@@ -172,13 +208,57 @@ impl Core {
         self.call_edit("scroll", Some(ArrayBuilder::new().push(start).push(end).build()));
     }
 
-    pub fn click(&mut self, line: u64, column: u64) {
-        self.call_edit("click", Some(ArrayBuilder::new().push(line).push(column).push(0).push(1).build()));
+    /// Click at the given position. `flags` is a combination of the `FLAG_*` modifiers,
+    /// `click_count` is 1 for a single click, 2 for a double-click (select word) and
+    /// 3 for a triple-click (select line).
+    pub fn click(&mut self, line: u64, column: u64, flags: u64, click_count: u64) {
+        self.call_edit("click", Some(ArrayBuilder::new().push(line).push(column).push(flags).push(click_count).build()));
     }
     pub fn drag(&mut self, line: u64, column: u64) {
         self.call_edit("drag", Some(ArrayBuilder::new().push(line).push(column).push(0).push(1).build()));
     }
 
+    /// Send a mouse gesture. `ty` is one of the gesture types understood by the core,
+    /// such as "point_select", "toggle_sel", "range_select", "word_select" or "line_select".
+    pub fn gesture(&mut self, line: u64, column: u64, ty: &str) {
+        self.call_edit("gesture", Some(ObjectBuilder::new()
+            .insert("line", line)
+            .insert("col", column)
+            .insert("ty", ty)
+            .build()));
+    }
+
+    /// Add a new cursor, or remove an existing one at the same position.
+    pub fn add_cursor(&mut self, line: u64, column: u64) {
+        self.gesture(line, column, "toggle_sel");
+    }
+
+    /// Add a selection for the next occurrence of the last selected text.
+    pub fn add_next_occurrence(&mut self) {
+        self.call_edit("find_next", Some(ObjectBuilder::new()
+            .insert("wrap_around", true)
+            .insert("allow_same", false)
+            .insert("modify_selection", "add")
+            .build()));
+    }
+
+    /// Replace all selections by one selection per (line, start, end) range.
+    pub fn select_columns(&mut self, ranges: &[(u64,u64,u64)]) {
+        for (i, &(line, start, end)) in ranges.iter().enumerate() {
+            self.gesture(line, start, if i == 0 { "point_select" } else { "toggle_sel" });
+            self.drag(line, end);
+        }
+    }
+
+    /// Return the whole text of the buffer of `n_lines` lines, leaving the cursors and
+    /// selections as they are.
+    pub fn buffer_text(&mut self, n_lines: u64) -> String {
+        let lines = self.render_lines_sync(0, n_lines);
+        lines.as_array().map_or(String::new(), |lines| {
+            lines.iter().filter_map(|line| line.as_array().and_then(|line| line[0].as_str())).collect()
+        })
+    }
+
     pub fn copy(&mut self) -> String {
         self.call_edit_sync("copy", None).as_str().map(|x|x.into()).unwrap()
     }
@@ -194,31 +274,21 @@ impl Core {
         self.render_lines(0, 10);
     }
 
-    pub fn render_lines(&mut self, _start: u64, _end: u64) {
-        unimplemented!()
-        // self.rpc_index += 1;
-        // println!("render_lines");
-        // let value = ArrayBuilder::new()
-        //     .push("rpc")
-        //     .push_object(|builder| builder
-        //         .insert("index", self.rpc_index)
-        //         .insert_array("request", |builder| builder
-        //             .push("render_lines")
-        //             .push_object(|builder| builder
-        //                 .insert("first_line", _start)
-        //                 .insert("last_line", _end)
-        //             )
-        //         )
-        //     ).unwrap();
-        // self.write(value);
-    }
-
-    pub fn render_lines_sync(&mut self, _start: u64, _end: u64) -> Value {
-        unimplemented!()
-        // self.render_lines(_start, _end);
-        // let value = self.rpc_rx.recv().unwrap();
-        // let object = value.as_object().unwrap();
-        // assert_eq!(self.rpc_index, object.get("index").unwrap().as_u64().unwrap());
-        // object.get("result").unwrap().clone()
+    /// Return the lines in the range `start .. end`, in the format of the "lines" of an
+    /// update. The view of the core is not changed.
+    pub fn render_lines_sync(&mut self, start: u64, end: u64) -> Value {
+        self.call_edit_sync("render_lines", Some(ObjectBuilder::new()
+            .insert("first_line", start)
+            .insert("last_line", end)
+            .build()))
+    }
+
+    /// Ask for the lines like `render_lines_sync` without waiting for them. Return the ID
+    /// of the request, to be given to `response`.
+    pub fn render_lines(&mut self, start: u64, end: u64) -> u64 {
+        self.call_edit_async("render_lines", Some(ObjectBuilder::new()
+            .insert("first_line", start)
+            .insert("last_line", end)
+            .build()))
     }
 }
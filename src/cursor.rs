@@ -0,0 +1,72 @@
+use std::time::{Duration, Instant};
+
+/// Time for which the cursor is shown (and then hidden) when blinking.
+const BLINK_INTERVAL_MS: u64 = 500;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CursorShape {
+    Block,
+    Bar,
+    Underline,
+}
+
+impl CursorShape {
+    pub fn from_name(name: &str) -> Option<CursorShape> {
+        match name {
+            "block" => Some(CursorShape::Block),
+            "bar" => Some(CursorShape::Bar),
+            "underline" => Some(CursorShape::Underline),
+            _ => None,
+        }
+    }
+}
+
+/// The appearance and blink state of the text cursors.
+pub struct Cursor {
+    pub shape: CursorShape,
+    pub blink: bool,
+    focused: bool,
+    blink_start: Instant, // the cursor is visible during the first interval after this
+}
+
+impl Cursor {
+    pub fn new(shape: CursorShape, blink: bool) -> Cursor {
+        Cursor { shape: shape, blink: blink, focused: true, blink_start: Instant::now() }
+    }
+
+    /// Show the cursor and restart blinking. Called on input, so that the cursor
+    /// stays solid while typing.
+    pub fn reset(&mut self) {
+        self.blink_start = Instant::now();
+    }
+
+    pub fn set_focused(&mut self, focused: bool) {
+        self.focused = focused;
+        self.reset();
+    }
+
+    /// An unfocused window shows a steady hollow cursor.
+    pub fn is_hollow(&self) -> bool {
+        !self.focused
+    }
+
+    pub fn is_visible(&self) -> bool {
+        if !self.blink || !self.focused {
+            return true;
+        }
+        let elapsed = self.blink_start.elapsed();
+        let elapsed_ms = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000;
+        (elapsed_ms / BLINK_INTERVAL_MS) % 2 == 0
+    }
+
+    /// Return when the cursor blinks next, or `None` if it stays as it is.
+    pub fn next_blink(&self) -> Option<Instant> {
+        if !self.blink || !self.focused {
+            return None;
+        }
+        let elapsed = self.blink_start.elapsed();
+        let elapsed_ms = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000;
+        let next_ms = (elapsed_ms / BLINK_INTERVAL_MS + 1) * BLINK_INTERVAL_MS;
+        Some(self.blink_start + Duration::from_millis(next_ms))
+    }
+}
@@ -5,13 +5,118 @@ use gtk;
 use gtk::prelude::*;
 use glib;
 
+use waker::Waker;
 
-pub fn open() -> mpsc::Receiver<Option<path::PathBuf>> {
-    spawn(Action::Open)
+
+pub fn open(waker: Waker) -> mpsc::Receiver<Option<path::PathBuf>> {
+    spawn(Action::Open, waker)
+}
+
+pub fn save(waker: Waker) -> mpsc::Receiver<Option<path::PathBuf>> {
+    spawn(Action::Save, waker)
+}
+
+/// The answer to the question what to do with unsaved changes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Confirm {
+    Save,
+    Discard,
+    Cancel,
+}
+
+/// Ask whether to save the changes to the named buffer before they are lost by `action`,
+/// e.g. "closing".
+pub fn confirm_unsaved(name: &str, action: &str, waker: Waker) -> mpsc::Receiver<Confirm> {
+    let (sx, rx) = mpsc::sync_channel(1);
+    let message = format!("Save changes to \"{}\" before {}?", name, action);
+    glib::idle_add(move || {
+        let dialog = gtk::MessageDialog::new(
+            None::<&gtk::Window>, gtk::DialogFlags::empty(), gtk::MessageType::Question,
+            gtk::ButtonsType::None, &message);
+        dialog.add_buttons(&[
+            ("Save", gtk::ResponseType::Yes.into()),
+            ("Discard", gtk::ResponseType::No.into()),
+            ("Cancel", gtk::ResponseType::Cancel.into()),
+            ]);
+
+        let response = dialog.run();
+        let answer = if response == gtk::ResponseType::Yes.into() {
+            Confirm::Save
+        } else if response == gtk::ResponseType::No.into() {
+            Confirm::Discard
+        } else {
+            Confirm::Cancel
+        };
+        sx.send(answer).unwrap();
+        waker.wake();
+        dialog.destroy();
+
+        glib::Continue(false)
+    });
+    rx
+}
+
+/// The answer to the question what to do when the file was changed by another program
+/// while the buffer has unsaved changes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reload {
+    Reload,
+    Keep,
+    Diff,
 }
 
-pub fn save() -> mpsc::Receiver<Option<path::PathBuf>> {
-    spawn(Action::Save)
+/// Ask whether to reload the named buffer from the disk.
+pub fn confirm_reload(name: &str, waker: Waker) -> mpsc::Receiver<Reload> {
+    let (sx, rx) = mpsc::sync_channel(1);
+    let message = format!("\"{}\" was changed on disk. Reload it and lose your changes?", name);
+    glib::idle_add(move || {
+        let dialog = gtk::MessageDialog::new(
+            None::<&gtk::Window>, gtk::DialogFlags::empty(), gtk::MessageType::Warning,
+            gtk::ButtonsType::None, &message);
+        dialog.add_buttons(&[
+            ("Reload", gtk::ResponseType::Yes.into()),
+            ("Keep my changes", gtk::ResponseType::No.into()),
+            ("Show differences", gtk::ResponseType::Apply.into()),
+            ]);
+
+        let response = dialog.run();
+        let answer = if response == gtk::ResponseType::Yes.into() {
+            Reload::Reload
+        } else if response == gtk::ResponseType::Apply.into() {
+            Reload::Diff
+        } else {
+            Reload::Keep
+        };
+        sx.send(answer).unwrap();
+        waker.wake();
+        dialog.destroy();
+
+        glib::Continue(false)
+    });
+    rx
+}
+
+/// Ask whether to restore the unsaved changes to the named buffer from a recovery file.
+pub fn confirm_restore(name: &str, waker: Waker) -> mpsc::Receiver<bool> {
+    let (sx, rx) = mpsc::sync_channel(1);
+    let message = format!("Unsaved changes to \"{}\" were recovered. Restore them?", name);
+    glib::idle_add(move || {
+        let dialog = gtk::MessageDialog::new(
+            None::<&gtk::Window>, gtk::DialogFlags::empty(), gtk::MessageType::Question,
+            gtk::ButtonsType::None, &message);
+        dialog.add_buttons(&[
+            ("Restore", gtk::ResponseType::Yes.into()),
+            ("Discard", gtk::ResponseType::No.into()),
+            ]);
+
+        let restore = dialog.run() == gtk::ResponseType::Yes.into();
+        sx.send(restore).unwrap();
+        waker.wake();
+        dialog.destroy();
+
+        glib::Continue(false)
+    });
+    rx
 }
 
 enum Action {
@@ -19,8 +124,8 @@ enum Action {
     Save,
 }
 
-fn spawn(action: Action) -> mpsc::Receiver<Option<path::PathBuf>> {
-    let (sx, rx) = mpsc::sync_channel(0);
+fn spawn(action: Action, waker: Waker) -> mpsc::Receiver<Option<path::PathBuf>> {
+    let (sx, rx) = mpsc::sync_channel(1);
     glib::idle_add(move || {
         let file_chooser = match action {
             Action::Open => {
@@ -48,6 +153,7 @@ fn spawn(action: Action) -> mpsc::Receiver<Option<path::PathBuf>> {
         } else {
             sx.send(None).unwrap();
         }
+        waker.wake();
         file_chooser.destroy();
 
         glib::Continue(false)
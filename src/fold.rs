@@ -0,0 +1,272 @@
+// Code folding.
+//
+// Folded regions hide buffer lines from the view, so the rows on the screen ("view lines")
+// no longer map one-to-one to buffer lines. `Folds` keeps the collapsed regions and maps
+// between the two. Everything sent to the core (scroll ranges, clicks) is in buffer lines.
+
+use brackets::{self, Pos};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FoldMarker {
+    None,
+    Foldable,
+    Folded,
+}
+
+/// The collapsed regions as (first, last) buffer lines. The first line of a region stays
+/// visible, the following lines up to and including the last one are hidden.
+pub struct Folds {
+    regions: Vec<(u64, u64)>, // sorted and disjoint
+}
+
+impl Folds {
+    pub fn new() -> Folds {
+        Folds { regions: vec![] }
+    }
+
+    pub fn clear(&mut self) {
+        self.regions.clear();
+    }
+
+    /// Collapse a region. Collapsed regions which overlap it are merged into it.
+    pub fn fold(&mut self, (first, last): (u64, u64)) {
+        if last <= first || self.is_hidden(first) {
+            return;
+        }
+        let last = self.regions.iter()
+            .filter(|&&(f, l)| l >= first && f <= last)
+            .fold(last, |last, &(_, l)| ::std::cmp::max(last, l));
+        self.regions.retain(|&(f, l)| l < first || f > last);
+        let i = self.regions.iter().position(|&(f, _)| f > first).unwrap_or(self.regions.len());
+        self.regions.insert(i, (first, last));
+    }
+
+    /// Expand the region starting at, or containing the given line. Return false if there
+    /// was no such region.
+    pub fn unfold(&mut self, line: u64) -> bool {
+        let len = self.regions.len();
+        self.regions.retain(|&(f, l)| line < f || line > l);
+        self.regions.len() != len
+    }
+
+    /// Move the regions after an edit at `pos` inserted (`delta` > 0) or removed
+    /// (`delta` < 0) lines. The lines are inserted or removed after the line of `pos`, or
+    /// from that line on if `pos` is at its start. The regions in which lines were
+    /// inserted or removed are expanded.
+    pub fn shift(&mut self, (line, column): Pos, delta: i64) {
+        // the last line which keeps its number
+        let kept = if column == 0 { line as i64 - 1 } else { line as i64 };
+        let removed = if delta < 0 { -delta } else { 0 };
+        self.regions = self.regions.iter().filter_map(|&(first, last)| {
+            if last as i64 <= kept {
+                Some((first, last))
+            } else if first as i64 > kept + removed {
+                Some(((first as i64 + delta) as u64, (last as i64 + delta) as u64))
+            } else {
+                None
+            }
+        }).collect();
+    }
+
+    pub fn is_folded(&self, line: u64) -> bool {
+        self.regions.iter().any(|&(f, _)| f == line)
+    }
+
+    pub fn is_hidden(&self, line: u64) -> bool {
+        self.regions.iter().any(|&(f, l)| line > f && line <= l)
+    }
+
+    /// Return the buffer line displayed on the given view line.
+    pub fn view_to_buffer(&self, view_line: u64) -> u64 {
+        let mut line = view_line;
+        for &(first, last) in &self.regions {
+            if first < line {
+                line += last - first;
+            } else {
+                break;
+            }
+        }
+        line
+    }
+
+    /// Return the view line on which the given buffer line is displayed. Hidden lines
+    /// map to the first line of their region.
+    pub fn buffer_to_view(&self, line: u64) -> u64 {
+        let mut view_line = line;
+        for &(first, last) in &self.regions {
+            if line > last {
+                view_line -= last - first;
+            } else {
+                if line > first {
+                    view_line -= line - first;
+                }
+                break;
+            }
+        }
+        view_line
+    }
+
+    /// Return the number of view lines for a buffer of `n_lines` lines.
+    pub fn view_line_count(&self, n_lines: u64) -> u64 {
+        let hidden: u64 = self.regions.iter()
+            .filter(|&&(first, _)| first < n_lines)
+            .map(|&(first, last)| ::std::cmp::min(last, n_lines - 1) - first)
+            .sum();
+        n_lines - hidden
+    }
+}
+
+fn indentation(text: &str, tab_width: u32) -> Option<u32> {
+    let mut indent = 0;
+    for c in text.chars() {
+        match c {
+            ' ' => indent += 1,
+            '\t' if tab_width > 0 => indent += tab_width - indent % tab_width,
+            '\t' => (),
+            '\n' | '\r' => return None, // blank line
+            _ => return Some(indent),
+        }
+    }
+    None
+}
+
+fn opening_bracket(text: &str) -> Option<usize> {
+    let trimmed = text.trim_end();
+    match trimmed.chars().next_back() {
+        Some('{') | Some('[') | Some('(') => Some(trimmed.len() - 1),
+        _ => None,
+    }
+}
+
+/// Return true if a region can be folded at the given line, judging from the line and
+/// the following one only.
+pub fn is_foldable<'t, F>(get_line: F, line: u64, tab_width: u32) -> bool
+    where F: Fn(u64) -> Option<&'t str>
+{
+    match (get_line(line), get_line(line + 1)) {
+        (Some(text), _) if opening_bracket(text).is_some() => true,
+        (Some(text), Some(next)) => {
+            match (indentation(text, tab_width), indentation(next, tab_width)) {
+                (Some(indent), Some(next_indent)) => next_indent > indent,
+                _ => false,
+            }
+        },
+        _ => false,
+    }
+}
+
+/// Return the region which can be folded at the given line. A line ending with an opening
+/// bracket folds up to the line before the matching bracket, other lines fold the
+/// following lines which are indented more.
+pub fn region_at<'t, F>(get_line: F, line: u64, tab_width: u32) -> Option<(u64, u64)>
+    where F: Fn(u64) -> Option<&'t str>
+{
+    let text = match get_line(line) { Some(text) => text, None => return None };
+
+    if let Some(column) = opening_bracket(text) {
+        if let Some((_, (end_line, _))) = brackets::find_pair(&get_line, (line, column as u64)) {
+            return if end_line > line + 1 { Some((line, end_line - 1)) } else { None };
+        }
+    }
+
+    let indent = match indentation(text, tab_width) { Some(indent) => indent, None => return None };
+    let mut last = line;
+    let mut n = line + 1;
+    while let Some(text) = get_line(n) {
+        match indentation(text, tab_width) {
+            Some(i) if i <= indent => break,
+            Some(_) => last = n,
+            None => (), // blank lines are folded only if followed by more indented ones
+        }
+        n += 1;
+    }
+    if last > line { Some((line, last)) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn folds(regions: &[(u64, u64)]) -> Folds {
+        let mut folds = Folds::new();
+        for &region in regions {
+            folds.fold(region);
+        }
+        folds
+    }
+
+    #[test]
+    fn view_to_buffer() {
+        // lines 3 to 5 and 9 to 10 are hidden
+        let folds = folds(&[(8, 10), (2, 5)]);
+        let lines: Vec<u64> = (0..8).map(|v| folds.view_to_buffer(v)).collect();
+        assert_eq!(lines, vec![0, 1, 2, 6, 7, 8, 11, 12]);
+        assert_eq!(folds.view_line_count(13), 8);
+        assert_eq!(folds.view_line_count(10), 6);
+    }
+
+    #[test]
+    fn buffer_to_view() {
+        let folds = folds(&[(2, 5), (8, 10)]);
+        let views: Vec<u64> = (0..13).map(|n| folds.buffer_to_view(n)).collect();
+        assert_eq!(views, vec![0, 1, 2, 2, 2, 2, 3, 4, 5, 5, 5, 6, 7]);
+        for v in 0..8 {
+            assert_eq!(folds.buffer_to_view(folds.view_to_buffer(v)), v);
+        }
+    }
+
+    #[test]
+    fn nested_regions_merge() {
+        let mut folds = folds(&[(4, 6)]);
+        folds.fold((2, 8));
+        assert_eq!(folds.regions, vec![(2, 8)]);
+        // a region starting inside a collapsed one can't be folded
+        folds.fold((3, 10));
+        assert_eq!(folds.regions, vec![(2, 8)]);
+    }
+
+    #[test]
+    fn overlapping_regions_merge() {
+        let mut folds = folds(&[(5, 10)]);
+        folds.fold((2, 7));
+        assert_eq!(folds.regions, vec![(2, 10)]);
+        assert!(folds.is_hidden(9));
+        assert!(!folds.is_folded(5));
+    }
+
+    #[test]
+    fn unfold() {
+        let mut folds = folds(&[(2, 5), (8, 10)]);
+        assert!(folds.unfold(4));
+        assert!(!folds.unfold(4));
+        assert_eq!(folds.regions, vec![(8, 10)]);
+    }
+
+    #[test]
+    fn shift_inserted_lines() {
+        let mut folds = folds(&[(2, 5), (8, 10)]);
+        // two lines inserted after line 6 move the second region only
+        folds.shift((6, 3), 2);
+        assert_eq!(folds.regions, vec![(2, 5), (10, 12)]);
+        // a line inserted at the start of line 2 moves the region starting there
+        folds.shift((2, 0), 1);
+        assert_eq!(folds.regions, vec![(3, 6), (11, 13)]);
+        // a line inserted in a region expands it
+        folds.shift((4, 1), 1);
+        assert_eq!(folds.regions, vec![(12, 14)]);
+    }
+
+    #[test]
+    fn shift_removed_lines() {
+        let mut folds = folds(&[(2, 5), (8, 10)]);
+        // joining line 7 with line 6
+        folds.shift((7, 0), -1);
+        assert_eq!(folds.regions, vec![(2, 5), (7, 9)]);
+        // removing lines 6 and 7, the first line of the second region
+        folds.shift((5, 4), -2);
+        assert_eq!(folds.regions, vec![(2, 5)]);
+        // removing lines before a region
+        folds.shift((0, 2), -1);
+        assert_eq!(folds.regions, vec![(1, 4)]);
+    }
+}
@@ -0,0 +1,144 @@
+// Finding the editor font.
+//
+// The font is configured by a file name or a family name. A family is looked up with
+// fontconfig's `fc-match`, and if it is not installed the Hack font built into the
+// binary is used, so the editor starts from any directory. The bold and italic faces
+// of a family are looked up as well; the missing ones are synthesised by `glium_text`.
+//
+// The characters missing in the font are taken from a chain of fallback fonts, given by
+// file or family names, or else from the font which fontconfig finds for each of them,
+// which covers every script installed on the system. Only the fonts actually needed are
+// opened.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::rc::Rc;
+
+use glium_text::{FindFallback, FontFamily};
+
+static BUILTIN_FONT: &'static [u8] = include_bytes!("../Hack-Regular.ttf");
+
+// the weight and slant of bold and italic faces in fontconfig
+const FC_WEIGHT_BOLD: u32 = 200;
+const FC_SLANT_ITALIC: u32 = 100;
+
+/// A font found by fontconfig.
+struct Match {
+    file: PathBuf,
+    family: String,
+    weight: u32,
+    slant: u32,
+}
+
+/// Return the best match of fontconfig for a pattern, e.g. "DejaVu Sans Mono" or
+/// "Hack:weight=bold".
+fn fc_match(pattern: &str) -> Option<Match> {
+    let output = match Command::new("fc-match").arg("--format=%{file}\t%{family[0]}\t%{weight}\t%{slant}").arg(pattern).output() {
+        Ok(ref output) if output.status.success() => String::from_utf8_lossy(&output.stdout).into_owned(),
+        _ => return None,
+    };
+    let fields: Vec<&str> = output.trim().split('\t').collect();
+    if fields.len() != 4 {
+        return None;
+    }
+    Some(Match {
+        file: PathBuf::from(fields[0]),
+        family: fields[1].into(),
+        weight: fields[2].parse().unwrap_or(0),
+        slant: fields[3].parse().unwrap_or(0),
+    }).filter(|m| m.file.is_file())
+}
+
+/// Return the match for a family name. Fontconfig substitutes another family for one
+/// which is not installed, which only counts for generic names like "monospace".
+fn find_family(name: &str) -> Option<Match> {
+    let family = name.split(':').next().unwrap_or(name).trim();
+    let generic = ["monospace", "mono", "sans-serif", "sans", "serif"];
+    fc_match(name).filter(|m| {
+        generic.contains(&family.to_lowercase().as_str()) || m.family.eq_ignore_ascii_case(family)
+    })
+}
+
+/// Return the path of a font given by a file name or a family name.
+pub fn find(name: &str) -> Option<PathBuf> {
+    let path = Path::new(name);
+    if path.is_file() {
+        Some(path.to_path_buf())
+    } else {
+        find_family(name).map(|m| m.file)
+    }
+}
+
+/// Return the fallback fonts given by file or family names.
+pub fn fallbacks(names: &[String]) -> Vec<PathBuf> {
+    names.iter().filter_map(|name| {
+        let path = find(name);
+        if path.is_none() {
+            eprintln!("fallback font {:?} not found", name);
+        }
+        path
+    }).collect()
+}
+
+/// Return a function finding the font closest to `font` which has a character. The fonts
+/// found are remembered, so fontconfig is asked once per character even when the glyphs
+/// are rasterised again at another size.
+pub fn find_fallback(font: &str) -> FindFallback {
+    let family = if Path::new(font).is_file() { "monospace".to_string() } else { font.to_string() };
+    let found: RefCell<HashMap<char, Option<PathBuf>>> = RefCell::new(HashMap::new());
+    Rc::new(move |c: char| {
+        found.borrow_mut().entry(c)
+            .or_insert_with(|| fc_match(&format!("{}:charset={:x}", family, c as u32)).map(|m| m.file))
+            .clone()
+    })
+}
+
+fn read(path: &Path) -> Option<Vec<u8>> {
+    let mut data = vec![];
+    File::open(path).and_then(|mut file| file.read_to_end(&mut data)).ok().map(|_| data)
+}
+
+/// Return the data of the font given by a file or family name, or of the built-in font.
+pub fn load(name: &str) -> Vec<u8> {
+    match find(name).and_then(|path| read(&path)) {
+        Some(data) => data,
+        None => {
+            eprintln!("font {:?} not found, using the built-in font", name);
+            BUILTIN_FONT.to_vec()
+        },
+    }
+}
+
+/// Return the faces of the family given by a file or family name. For a file or the
+/// built-in font, only the regular face is known.
+pub fn load_family(name: &str) -> FontFamily<Vec<u8>> {
+    if Path::new(name).is_file() {
+        return FontFamily::new(load(name));
+    }
+    let regular = match find_family(name) {
+        Some(regular) => regular,
+        None => return FontFamily::new(load(name)),
+    };
+
+    // a face of the same family with the given weight and slant
+    let face = |bold: bool, italic: bool| -> Option<Vec<u8>> {
+        let pattern = format!("{}:weight={}:slant={}", regular.family,
+                              if bold { "bold" } else { "regular" }, if italic { "italic" } else { "roman" });
+        fc_match(&pattern)
+            .filter(|m| m.family == regular.family && m.file != regular.file)
+            .filter(|m| (m.weight >= FC_WEIGHT_BOLD) == bold && (m.slant >= FC_SLANT_ITALIC) == italic)
+            .and_then(|m| read(&m.file))
+    };
+    FontFamily {
+        bold: face(true, false),
+        italic: face(false, true),
+        bold_italic: face(true, true),
+        regular: read(&regular.file).unwrap_or(BUILTIN_FONT.to_vec()),
+        fallbacks: vec![],
+        find_fallback: None,
+    }
+}
@@ -4,6 +4,18 @@ mod renderer;
 mod controller;
 mod text;
 mod file_dialog;
+mod config;
+mod cursor;
+mod unicode;
+mod brackets;
+mod fold;
+mod minimap;
+mod status_bar;
+mod watcher;
+mod recovery;
+mod waker;
+mod png;
+mod font;
 
 #[macro_use]
 extern crate glium;
@@ -12,22 +24,49 @@ extern crate serde_json;
 extern crate gtk;
 extern crate glib; // Needed by gtk to supply a threaded fn idle_add
 extern crate clipboard;
+#[cfg(target_os = "linux")]
+extern crate libc;
+
+/// The initial size of the window, and the size of snapshots.
+const WIDTH: u32 = 760;
+const HEIGHT: u32 = 380;
 
 fn main() {
-    let filename = std::env::args().nth(1);
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
     let core_path = std::env::var("xicore").unwrap_or("../xi-editor/rust/target/debug/xi-core".into());
+    let config = config::Config::from_env();
+
+    // `xi_glium --snapshot out.png [file]` renders the file without a window, e.g. on a
+    // machine without a GPU using Mesa's software renderer.
+    if args.first().map_or(false, |arg| arg == "--snapshot") {
+        if args.len() < 2 {
+            eprintln!("usage: xi_glium --snapshot <output.png> [file]");
+            std::process::exit(2);
+        }
+        let output = args.remove(1);
+        let filename = args.get(1).cloned();
+        use glium::DisplayBuild;
+        let display = glium::glutin::HeadlessRendererBuilder::new(WIDTH, HEIGHT).build_glium().unwrap();
+        if let Err(e) = controller::snapshot(&core_path, filename, display, config, (WIDTH, HEIGHT),
+                                             std::path::Path::new(&output)) {
+            eprintln!("could not save the snapshot to {}: {}", output, e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let filename = args.into_iter().next();
 
     // I read that GTK on Mac needs to be in the main thread. We must let it have it.
     ::std::thread::spawn(move || {
         use glium::DisplayBuild;
         let display = glium::glutin::WindowBuilder::new()
-            .with_dimensions(760, 380)
+            .with_dimensions(WIDTH, HEIGHT)
             .with_title(String::from("xi_glium"))
             .build_glium()
             .unwrap();
         display.get_window().unwrap().set_cursor(glium::glutin::MouseCursor::Text);
 
-        controller::run(&core_path, filename, display);
+        controller::run(&core_path, filename, display, config);
 
         glib::idle_add(|| { gtk::main_quit(); glib::Continue(false) });
     });
@@ -0,0 +1,128 @@
+// The minimap: a scaled-down overview of the buffer, left of the scrollbar.
+//
+// Every line is drawn as a row of blocks, one per non-blank run of characters. When the
+// whole buffer does not fit, the minimap scrolls along with the text so that its top
+// reaches the top of the buffer and its bottom the end of the buffer together with the
+// view. The lines shown are requested from the core apart from the visible text, and
+// only their text and markers are kept, as they are not laid out with the font.
+
+use renderer::*;
+
+// in logical pixels
+const WIDTH: f32 = 80.;
+const ROW_HEIGHT: f32 = 2.;
+const COLUMN_WIDTH: f32 = 1.;
+const SCROLLBAR_WIDTH: f32 = 20.;
+const MARKER_WIDTH: f32 = 4.;
+
+/// What the minimap shows of a line.
+pub struct MinimapLine {
+    pub text: String,
+    pub finds: bool, // whether the line has search hits
+    pub selection: bool, // whether the line has a non-empty selection
+}
+
+pub struct Minimap {
+    top: f64, // the view line at the top of the minimap
+    rows: f64,
+    scale: f32, // see `Renderer::scale`
+    left: f32, // x-position of the left edge
+    bottom: f32, // y-position of the bottom edge
+    window_height: f32,
+}
+
+impl Minimap {
+    /// Lay out the minimap for a window, given its scale, the space left below it, the
+    /// view (`top` and `height`) and the number of view lines.
+    pub fn new((w, h): (f32, f32), scale: f32, bottom: f32, top: f64, height: f64, n_lines: u64) -> Minimap {
+        let rows = (h - bottom) as f64 / (ROW_HEIGHT * scale) as f64;
+        let n_lines = n_lines as f64;
+        let top = if n_lines <= rows || n_lines <= height {
+            0.
+        } else {
+            top / (n_lines - height) * (n_lines - rows)
+        };
+        Minimap {
+            top: top, rows: rows, scale: scale,
+            left: w - (SCROLLBAR_WIDTH + WIDTH) * scale, bottom: bottom, window_height: h,
+        }
+    }
+
+    pub fn contains(&self, px: i32) -> bool {
+        let px = px as f32;
+        px >= self.left && px < self.left + WIDTH * self.scale
+    }
+
+    /// Return the view line shown at the y-coordinate `py`, measured from the top.
+    pub fn view_line_at(&self, py: i32) -> f64 {
+        self.top + py as f64 / (ROW_HEIGHT * self.scale) as f64
+    }
+
+    /// Return the range of view lines shown in the minimap.
+    pub fn view_range(&self) -> (u64, u64) {
+        (self.top as u64, (self.top + self.rows).ceil() as u64)
+    }
+
+    fn row_y(&self, view_line: f64) -> f32 {
+        self.window_height - (view_line - self.top) as f32 * ROW_HEIGHT * self.scale
+    }
+
+    /// Draw the minimap. `lines` are the known lines in `view_range` with their view
+    /// line, `top` and `height` give the visible region of the text.
+    pub fn draw(&self, target: &mut Target, lines: &[(u64, &MinimapLine)], top: f64, height: f64, tab_width: u32) {
+        let s = self.scale;
+        let (x0, x1) = (self.left, self.left + WIDTH * s);
+        let (h, text_color) = (self.window_height, [0.55,0.55,0.55,1.]);
+
+        target.rect((x0, self.bottom), (x1, h), [0.97,0.97,0.97,1.]);
+
+        let mut markers = vec![];
+        for &(view_line, line) in lines {
+            let y = self.row_y(view_line as f64);
+            for (start, end) in runs(&line.text, tab_width) {
+                let start = x0 + start as f32 * COLUMN_WIDTH * s;
+                let end = (x0 + end as f32 * COLUMN_WIDTH * s).min(x1 - MARKER_WIDTH * s);
+                if start < end {
+                    target.rect((start, y - (ROW_HEIGHT - 0.5) * s), (end, y), text_color);
+                }
+            }
+            let marker = if line.finds {
+                Some([1.,0.6,0.,1.])
+            } else if line.selection {
+                Some([0.5,0.5,1.,1.])
+            } else {
+                None
+            };
+            if let Some(color) = marker {
+                markers.push(((x1 - MARKER_WIDTH * s, y - ROW_HEIGHT * s), (x1, y), color));
+            }
+        }
+        for (p1, p2, color) in markers {
+            target.rect(p1, p2, color);
+        }
+
+        // the visible region
+        let (y0, y1) = (self.row_y(top), self.row_y(top + height));
+        target.rect((x0, y0), (x1, y1), [0.,0.,0.,0.08]);
+    }
+}
+
+/// Return the column ranges of the runs of non-blank characters.
+fn runs(text: &str, tab_width: u32) -> Vec<(u32, u32)> {
+    let mut runs = vec![];
+    let mut column = 0;
+    let mut start = None;
+    for c in text.chars() {
+        let blank = c.is_whitespace();
+        match (blank, start) {
+            (true, Some(s)) => { runs.push((s, column)); start = None; },
+            (false, None) => start = Some(column),
+            _ => (),
+        }
+        column += if c == '\t' && tab_width > 0 { tab_width - column % tab_width } else { 1 };
+    }
+    if let Some(s) = start {
+        runs.push((s, column));
+    }
+    runs
+}
@@ -0,0 +1,189 @@
+// Writing images as PNG files.
+//
+// The images are snapshots of the view, for bug reports and for comparing the rendering
+// with a reference image, so the encoder is kept minimal: the pixels are stored as
+// 8-bit RGBA, without filtering and in uncompressed deflate blocks.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// An RGBA image with 8 bits per channel, stored row by row from the top.
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    /// Make an image from rows of pixels given from the bottom, as OpenGL reads them.
+    pub fn from_rows_bottom_up(rows: Vec<Vec<(u8, u8, u8, u8)>>) -> Image {
+        let height = rows.len() as u32;
+        let width = rows.first().map_or(0, |row| row.len() as u32);
+        let mut pixels = Vec::with_capacity((width * height * 4) as usize);
+        for row in rows.iter().rev() {
+            for &(r, g, b, a) in row {
+                pixels.extend_from_slice(&[r, g, b, a]);
+            }
+        }
+        Image { width: width, height: height, pixels: pixels }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        self.write(&mut file)?;
+        file.flush()
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&be32(self.width));
+        header.extend_from_slice(&be32(self.height));
+        header.extend_from_slice(&[8, 6, 0, 0, 0]); // 8 bits, RGBA, deflate, no filter, no interlace
+        write_chunk(w, b"IHDR", &header)?;
+
+        // every row starts with its filter type, 0 for none
+        let stride = self.width as usize * 4;
+        let mut raw = Vec::with_capacity((stride + 1) * self.height as usize);
+        for row in self.pixels.chunks(stride.max(1)).take(self.height as usize) {
+            raw.push(0);
+            raw.extend_from_slice(row);
+        }
+        write_chunk(w, b"IDAT", &zlib_stored(&raw))?;
+
+        write_chunk(w, b"IEND", &[])
+    }
+}
+
+fn be32(n: u32) -> [u8; 4] {
+    [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+}
+
+fn write_chunk<W: Write>(w: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    w.write_all(&be32(data.len() as u32))?;
+    w.write_all(kind)?;
+    w.write_all(data)?;
+    let crc = crc32(crc32(0, kind), data);
+    w.write_all(&be32(crc))
+}
+
+/// Wrap the data in a zlib stream of uncompressed deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    const MAX_BLOCK: usize = 65535;
+    let mut out = Vec::with_capacity(data.len() + data.len() / MAX_BLOCK * 5 + 11);
+    out.extend_from_slice(&[0x78, 0x01]);
+    let mut blocks = data.chunks(MAX_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        out.push(last as u8);
+        out.extend_from_slice(&[len as u8, (len >> 8) as u8, !len as u8, (!len >> 8) as u8]);
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&be32(adler32(data)));
+    out
+}
+
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_be32(data: &[u8]) -> u32 {
+        (data[0] as u32) << 24 | (data[1] as u32) << 16 | (data[2] as u32) << 8 | data[3] as u32
+    }
+
+    // Returns the chunks of a PNG file, checking their CRCs.
+    fn chunks(mut png: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        png = &png[8..];
+        let mut chunks = vec![];
+        while !png.is_empty() {
+            let len = read_be32(png) as usize;
+            let (kind, data) = (&png[4..8], &png[8..8 + len]);
+            assert_eq!(read_be32(&png[8 + len..]), crc32(crc32(0, kind), data));
+            chunks.push((kind.to_vec(), data.to_vec()));
+            png = &png[12 + len..];
+        }
+        chunks
+    }
+
+    // Returns the data of a zlib stream of uncompressed blocks, checking its checksum.
+    fn unstore(zlib: &[u8]) -> Vec<u8> {
+        assert_eq!(&zlib[..2], &[0x78, 0x01]);
+        let mut data = vec![];
+        let mut pos = 2;
+        loop {
+            let last = zlib[pos] == 1;
+            let len = zlib[pos + 1] as usize | (zlib[pos + 2] as usize) << 8;
+            assert_eq!(!(len as u16), zlib[pos + 3] as u16 | (zlib[pos + 4] as u16) << 8);
+            data.extend_from_slice(&zlib[pos + 5..pos + 5 + len]);
+            pos += 5 + len;
+            if last {
+                break;
+            }
+        }
+        assert_eq!(read_be32(&zlib[pos..]), adler32(&data));
+        assert_eq!(pos + 4, zlib.len());
+        data
+    }
+
+    #[test]
+    fn known_checksums() {
+        assert_eq!(crc32(0, b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn round_trip() {
+        let image = Image::from_rows_bottom_up(vec![
+            vec![(1, 2, 3, 4), (5, 6, 7, 8)],
+            vec![(9, 10, 11, 12), (13, 14, 15, 16)],
+        ]);
+        let mut png = vec![];
+        image.write(&mut png).unwrap();
+
+        let chunks = chunks(&png);
+        let kinds: Vec<&[u8]> = chunks.iter().map(|&(ref kind, _)| &kind[..]).collect();
+        assert_eq!(kinds, vec![&b"IHDR"[..], &b"IDAT"[..], &b"IEND"[..]]);
+        assert_eq!(chunks[0].1, vec![0, 0, 0, 2, 0, 0, 0, 2, 8, 6, 0, 0, 0]);
+        // the top row first, each after its filter type
+        assert_eq!(unstore(&chunks[1].1), vec![0, 9, 10, 11, 12, 13, 14, 15, 16, 0, 1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(chunks[2].1.is_empty());
+    }
+
+    #[test]
+    fn several_blocks() {
+        let data: Vec<u8> = (0..150000u32).map(|i| i as u8).collect();
+        assert_eq!(unstore(&zlib_stored(&data)), data);
+        assert!(unstore(&zlib_stored(&[])).is_empty());
+    }
+}
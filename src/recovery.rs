@@ -0,0 +1,151 @@
+// Recovery files for crashes.
+//
+// Modified buffers are saved from time to time to a recovery file in
+// `$XDG_STATE_HOME/xi_glium/recovery` (`~/.local/state/xi_glium/recovery` by default).
+// The name of a recovery file is the absolute path of the edited file with `%` and `/`
+// escaped, or `untitled-<pid>` for a buffer without a file. The recovery file is removed
+// when the buffer is saved or its changes are discarded, so a recovery file found on
+// startup is left over from a crash.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Return the directory of the recovery files.
+pub fn dir() -> Option<PathBuf> {
+    let state = match env::var("XDG_STATE_HOME") {
+        Ok(ref dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => match env::var("HOME") {
+            Ok(home) => Path::new(&home).join(".local/state"),
+            Err(_) => return None,
+        },
+    };
+    Some(state.join("xi_glium").join("recovery"))
+}
+
+fn escape(path: &Path) -> String {
+    path.to_string_lossy().replace('%', "%25").replace('/', "%2F")
+}
+
+/// Return the recovery file of the buffer editing `filename`.
+pub fn path(filename: Option<&str>) -> Option<PathBuf> {
+    let name = match filename {
+        Some(filename) => {
+            let path = Path::new(filename);
+            escape(&fs::canonicalize(path).unwrap_or(path.to_path_buf()))
+        },
+        None => format!("untitled-{}", process::id()),
+    };
+    dir().map(|dir| dir.join(name))
+}
+
+/// Return the path of the recovery file, creating its directory if needed.
+pub fn create(filename: Option<&str>) -> io::Result<PathBuf> {
+    let path = path(filename).ok_or(io::Error::new(io::ErrorKind::NotFound, "no home directory"))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    Ok(path)
+}
+
+/// Return a recovery file left over for `filename`. Without a file, return the newest
+/// recovery file of an untitled buffer from a process that is no longer running.
+pub fn find(filename: Option<&str>) -> Option<PathBuf> {
+    match filename {
+        Some(_) => path(filename).filter(|p| p.is_file()),
+        None => {
+            let entries = match dir().and_then(|dir| fs::read_dir(dir).ok()) {
+                Some(entries) => entries,
+                None => return None,
+            };
+            entries.filter_map(|e| e.ok())
+                .filter(|e| {
+                    let name = e.file_name().to_string_lossy().into_owned();
+                    name.starts_with("untitled-") &&
+                        name["untitled-".len()..].parse().map_or(false, |pid| !running(pid))
+                })
+                .filter_map(|e| e.metadata().and_then(|m| m.modified()).ok().map(|t| (t, e.path())))
+                .max()
+                .map(|(_, path)| path)
+        },
+    }
+}
+
+// A process that is still running owns its recovery file and saves to it again later.
+fn running(pid: u32) -> bool {
+    pid == process::id() || Path::new("/proc").join(pid.to_string()).exists()
+}
+
+/// Remove the recovery file of the buffer editing `filename`, if there is one.
+pub fn remove(filename: Option<&str>) {
+    if let Some(path) = path(filename) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use std::sync::Once;
+
+    static STATE_HOME: Once = Once::new();
+
+    // Points `XDG_STATE_HOME` to a directory of this test run, shared by the tests.
+    fn state_home() -> PathBuf {
+        let home = env::temp_dir().join(format!("xi_glium-test-{}", process::id()));
+        STATE_HOME.call_once(|| env::set_var("XDG_STATE_HOME", &home));
+        home
+    }
+
+    fn touch(path: &Path) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::File::create(path).unwrap();
+    }
+
+    #[test]
+    fn escape_paths() {
+        assert_eq!(escape(Path::new("/home/a%b/c.rs")), "%2Fhome%2Fa%25b%2Fc.rs");
+        assert_eq!(escape(Path::new("x")), "x");
+    }
+
+    #[test]
+    fn recovery_paths() {
+        let home = state_home();
+        let recovery = home.join("xi_glium/recovery");
+        assert_eq!(dir(), Some(recovery.clone()));
+        assert_eq!(path(None), Some(recovery.join(format!("untitled-{}", process::id()))));
+        assert_eq!(path(Some("/nonexistent/f.rs")), Some(recovery.join("%2Fnonexistent%2Ff.rs")));
+    }
+
+    #[test]
+    fn find_file() {
+        state_home();
+        let filename = "/nonexistent/find_file.rs";
+        assert_eq!(find(Some(filename)), None);
+        let recovery = create(Some(filename)).unwrap();
+        touch(&recovery);
+        assert_eq!(find(Some(filename)), Some(recovery));
+        remove(Some(filename));
+        assert_eq!(find(Some(filename)), None);
+    }
+
+    #[test]
+    fn find_untitled() {
+        let dir = state_home().join("xi_glium/recovery");
+        let mut exited = Command::new("true").spawn().unwrap();
+        exited.wait().unwrap();
+        let mut running = Command::new("sleep").arg("10").spawn().unwrap();
+
+        for name in &[format!("untitled-{}", process::id()), format!("untitled-{}", running.id()), "untitled-x".into()] {
+            touch(&dir.join(name));
+        }
+        assert_eq!(find(None), None);
+        let left = dir.join(format!("untitled-{}", exited.id()));
+        touch(&left);
+        assert_eq!(find(None), Some(left));
+        let _ = running.kill();
+    }
+}
@@ -1,42 +1,176 @@
 
-use std::path::Path;
-use std::fs::File;
+use std::cell::RefCell;
+use std::rc::Rc;
 
 use glium;
 use glium_text;
+use glium_text::FontStyle;
 use glium::Surface;
 use glium::index::PrimitiveType;
 
+use config::Config;
+use font;
+use png::Image;
+
+/// Where a `Target` draws: the frame of the window, or a texture drawn offscreen.
+enum Output {
+    Window(glium::Frame),
+    Texture(glium::texture::Texture2d),
+}
+
+/// A frame being drawn, in the window or offscreen.
+///
+/// Rectangles, lines and text are not drawn right away, but collected in two batches:
+/// one of triangles for the shapes and one of glyphs for the text. `flush` draws the
+/// shapes and then the text, with one draw call each. So everything added before a
+/// flush is drawn under everything added after it, and text is drawn over the shapes
+/// added together with it.
 pub struct Target<'a> {
-    target: glium::Frame,
+    output: Output,
     // TODO: make it somehow not public (is public to enable
     // creating new objects from Target).
     pub renderer: &'a Renderer,
+    batch: Vec<Vertex>,
 }
 
 impl<'a> Target<'a> {
     pub fn get_dimensions(&self) -> (u32,u32) {
-        self.target.get_dimensions()
+        match self.output {
+            Output::Window(ref frame) => frame.get_dimensions(),
+            Output::Texture(ref texture) => (texture.get_width(), texture.get_height().unwrap_or(1)),
+        }
+    }
+
+    /// Add a filled rectangle given by two opposite corners.
+    pub fn rect(&mut self, p1: (f32,f32), p2: (f32,f32), color: [f32; 4]) {
+        self.quad([(p1.0, p1.1), (p2.0, p1.1), (p1.0, p2.1), (p2.0, p2.1)], color);
+    }
+
+    /// Add the outline of a rectangle, one logical pixel wide.
+    pub fn rect_outline(&mut self, p1: (f32,f32), p2: (f32,f32), color: [f32; 4]) {
+        self.line((p1.0, p1.1), (p2.0, p1.1), color);
+        self.line((p2.0, p1.1), (p2.0, p2.1), color);
+        self.line((p2.0, p2.1), (p1.0, p2.1), color);
+        self.line((p1.0, p2.1), (p1.0, p1.1), color);
+    }
+
+    /// Add a line, one logical pixel wide. Like a line drawn by OpenGL, it covers the
+    /// pixels whose centers it passes through.
+    pub fn line(&mut self, p1: (f32,f32), p2: (f32,f32), color: [f32; 4]) {
+        let (dx, dy) = (p2.0 - p1.0, p2.1 - p1.1);
+        let length = (dx * dx + dy * dy).sqrt();
+        if length == 0. {
+            return;
+        }
+        // half the width along the line and across it
+        let half_width = self.renderer.scale.round().max(1.) / 2.;
+        let (ax, ay) = (dx / length * half_width, dy / length * half_width);
+        let (nx, ny) = (-ay, ax);
+        self.quad([(p1.0 - ax + nx, p1.1 - ay + ny), (p1.0 - ax - nx, p1.1 - ay - ny),
+                   (p2.0 + ax + nx, p2.1 + ay + ny), (p2.0 + ax - nx, p2.1 + ay - ny)], color);
+    }
+
+    // Add a quad given by its corners in the order of a triangle strip.
+    fn quad(&mut self, corners: [(f32,f32); 4], color: [f32; 4]) {
+        for &i in &[0, 1, 2, 2, 1, 3] {
+            let (x, y) = corners[i];
+            self.batch.push(Vertex { position: [x, y], color: color });
+        }
+    }
+
+    /// Draw the shapes and then the text collected so far.
+    pub fn flush(&mut self) {
+        match self.output {
+            Output::Window(ref mut frame) => flush(self.renderer, &mut self.batch, frame),
+            Output::Texture(ref texture) => flush(self.renderer, &mut self.batch, &mut texture.as_surface()),
+        }
+    }
+
+    pub fn finish(mut self) {
+        self.flush();
+        if let Output::Window(frame) = self.output {
+            frame.finish().unwrap();
+        }
+    }
+
+    /// Finish drawing and return the image drawn offscreen, or `None` for the window.
+    pub fn read(mut self) -> Option<Image> {
+        self.flush();
+        match self.output {
+            Output::Window(frame) => {
+                frame.finish().unwrap();
+                None
+            },
+            Output::Texture(texture) => {
+                let mut image = Image::from_rows_bottom_up(texture.read());
+                // blending leaves the edges of glyphs translucent, but the window is opaque
+                for pixel in image.pixels.chunks_mut(4) {
+                    pixel[3] = 255;
+                }
+                Some(image)
+            },
+        }
+    }
+}
+
+fn flush<S: Surface>(renderer: &Renderer, batch: &mut Vec<Vertex>, surface: &mut S) {
+    let (w, h) = surface.get_dimensions();
+    if !batch.is_empty() {
+        let mut buffer = renderer.batch_buffer.borrow_mut();
+        if buffer.as_ref().map_or(true, |b| b.len() < batch.len()) {
+            let size = batch.len().next_power_of_two();
+            *buffer = Some(glium::VertexBuffer::empty_dynamic(&renderer.display, size).unwrap());
+        }
+        let slice = buffer.as_ref().unwrap().slice(0 .. batch.len()).unwrap();
+        slice.write(&batch[..]);
+
+        let params = glium::DrawParameters {
+            blend: glium::draw_parameters::Blend::alpha_blending(),
+            ..Default::default()
+        };
+        surface.draw(slice, &glium::index::NoIndices(PrimitiveType::TrianglesList), &renderer.program,
+                     &uniform!{ win_size: (w as f32, h as f32) }, &params).unwrap();
+        batch.clear();
     }
 
-    pub fn finish(self) {
-        self.target.finish().unwrap();
+    let mut text_batch = renderer.text_batch.borrow_mut();
+    if !text_batch.is_empty() {
+        // the glyphs are positioned in pixels
+        let matrix = [[2. / w as f32, 0.0, 0.0, 0.0],
+                      [0.0, 2. / h as f32, 0.0, 0.0],
+                      [0.0, 0.0, 1.0, 0.0],
+                      [-1.0, -1.0, 0.0, 1.0]];
+        glium_text::draw_batch(&mut text_batch, &renderer.text_system, &renderer.font_texture,
+                               surface, matrix);
     }
 }
 
 pub struct Renderer {
+    pub config: Config,
     display: glium::backend::glutin_backend::GlutinFacade,
     program: glium::Program,
     text_system: glium_text::TextSystem,
-    font_texture: glium_text::FontTexture,
+    font: glium_text::FontFamily<Vec<u8>>, // the data of the font files, kept to build the texture again at another size
+    font_size: u32, // in logical pixels
+    scale: f32, // physical pixels per logical pixel
+    font_texture: Rc<glium_text::FontTexture>, // shared with the `LineRenderer`s built with it
+    batch_buffer: RefCell<Option<glium::VertexBuffer<Vertex>>>, // reused by every frame, grown as needed
+    text_batch: RefCell<glium_text::TextBatch>,
 }
 
 impl Renderer {
-    pub fn new(display: glium::backend::glutin_backend::GlutinFacade) -> Renderer {
-        let font_size = 15;
+    pub fn new(display: glium::backend::glutin_backend::GlutinFacade, config: Config, scale: f32) -> Renderer {
+        let mut font = font::load_family(&config.font);
+        if config.font_fallback.is_empty() {
+            font.find_fallback = Some(font::find_fallback(&config.font));
+        } else {
+            font.fallbacks = font::fallbacks(&config.font_fallback);
+        }
+        let font_size = config.font_size;
 
         let text_system = glium_text::TextSystem::new(&display);
-        let font_texture = glium_text::FontTexture::new(&display, File::open(&Path::new("Hack-Regular.ttf")).unwrap(), font_size).unwrap();
+        let text_batch = glium_text::TextBatch::new(&text_system);
+        let font_texture = build_font_texture(&display, &font, scaled_font_size(font_size, scale));
 
         let program = program!(&display,
             140 => {
@@ -46,10 +180,9 @@ impl Renderer {
                     in vec4 color;
                     out vec4 v_color;
                     uniform vec2 win_size;
-                    uniform vec2 offset;
                     void main() {
                         v_color = color;
-                        gl_Position = vec4((position + offset) / win_size * 2. - 1., 0.0, 1.0);
+                        gl_Position = vec4(position / win_size * 2. - 1., 0.0, 1.0);
                     }
                 ",
                 fragment: "
@@ -70,11 +203,10 @@ impl Renderer {
                     varying vec4 v_color;
 
                     uniform vec2 win_size;
-                    uniform vec2 offset;
 
                     void main() {
                         v_color = color;
-                        gl_Position = vec4((position + offset) / win_size * 2. - 1., 0.0, 1.0);
+                        gl_Position = vec4(position / win_size * 2. - 1., 0.0, 1.0);
                     }
                 ",
                 fragment: "
@@ -89,110 +221,250 @@ impl Renderer {
         }).unwrap();
 
         Renderer {
+            config: config,
             display: display,
             program: program,
             text_system: text_system,
-            font_texture: font_texture,
+            font: font,
+            font_size: font_size,
+            scale: scale,
+            font_texture: Rc::new(font_texture),
+            batch_buffer: RefCell::new(None),
+            text_batch: RefCell::new(text_batch),
         }
     }
 
+    pub fn font_size(&self) -> u32 {
+        self.font_size
+    }
+
+    /// Change the font size in logical pixels. See `set_scale`.
+    pub fn set_font_size(&mut self, font_size: u32) {
+        self.font_size = font_size;
+        self.build_font_texture();
+    }
+
+    /// The number of physical pixels per logical pixel, 2 on a typical HiDPI screen.
+    /// The sizes of the layout are given in logical pixels and multiplied by this.
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    /// Change the scale, and build the font texture again at the new size in physical
+    /// pixels. The `LineRenderer`s built before keep the old texture, so they have to
+    /// be built again.
+    pub fn set_scale(&mut self, scale: f32) {
+        self.scale = scale;
+        self.build_font_texture();
+    }
+
+    fn build_font_texture(&mut self) {
+        let size = scaled_font_size(self.font_size, self.scale);
+        let font_texture = build_font_texture(&self.display, &self.font, size);
+        self.font_texture = Rc::new(font_texture);
+    }
+
+    /// The distance between two lines of text in pixels, as recommended by the font.
+    pub fn line_height(&self) -> f32 {
+        (self.font_texture.line_height() * self.font_texture.em_pixels() as f32).ceil()
+    }
+
+    /// The size of an em-unit of the editor font in pixels.
+    pub fn em_pixels(&self) -> u32 {
+        self.font_texture.em_pixels()
+    }
+
+    /// The width of a column in pixels. This is the advance of a space, which is the
+    /// advance of any character in a monospace font.
+    pub fn column_width(&self) -> f32 {
+        self.font_texture.char_advance(' ').unwrap_or(0.5) * self.font_texture.em_pixels() as f32
+    }
+
     pub fn draw(&self) -> Target {
         let mut target = self.display.draw();
         target.clear_color(1.0, 1.0, 1.0, 0.0);
-        Target { target: target, renderer: &self }
+        Target { output: Output::Window(target), renderer: &self, batch: vec![] }
+    }
+
+    /// Start drawing a frame of the given size into a texture instead of the window.
+    /// `Target::read` returns the image. This also works with a headless display, for
+    /// example on Mesa's software renderer.
+    pub fn draw_offscreen(&self, width: u32, height: u32) -> Target {
+        let texture = glium::texture::Texture2d::empty_with_format(&self.display,
+            glium::texture::UncompressedFloatFormat::U8U8U8U8, glium::texture::MipmapsOption::NoMipmap,
+            width, height).unwrap();
+        texture.as_surface().clear_color(1.0, 1.0, 1.0, 1.0);
+        Target { output: Output::Texture(texture), renderer: &self, batch: vec![] }
     }
 }
 
-pub struct LineRenderer<'a> {
-    text_display: glium_text::TextDisplay<&'a glium_text::FontTexture>,
+fn build_font_texture(display: &glium::backend::glutin_backend::GlutinFacade, font: &glium_text::FontFamily<Vec<u8>>,
+                      size: u32) -> glium_text::FontTexture {
+    let family = glium_text::FontFamily {
+        regular: &font.regular[..],
+        bold: font.bold.as_ref().map(|data| &data[..]),
+        italic: font.italic.as_ref().map(|data| &data[..]),
+        bold_italic: font.bold_italic.as_ref().map(|data| &data[..]),
+        fallbacks: font.fallbacks.clone(),
+        find_fallback: font.find_fallback.clone(),
+    };
+    glium_text::FontTexture::with_family(display, family, size).unwrap()
+}
+
+fn scaled_font_size(font_size: u32, scale: f32) -> u32 {
+    ((font_size as f32 * scale).round() as u32).max(1)
+}
+
+pub struct LineRenderer {
+    text_display: glium_text::TextDisplay<Rc<glium_text::FontTexture>>,
     pub char_pos_x: Vec<f32>, // in screen coordinates
+    // start and end x-positions of the characters which get a marker
+    tabs: Vec<(f32, f32)>,
+    spaces: Vec<(f32, f32)>,
+    nb_spaces: Vec<(f32, f32)>,
+    placeholders: Vec<(f32, f32)>,
 }
 
-impl<'a> LineRenderer<'a> {
-    pub fn new(renderer: &'a Renderer, text: &str) -> LineRenderer<'a> {
-        let text_display = glium_text::TextDisplay::new(&renderer.text_system, &renderer.font_texture, text);
-        let em_pixels = renderer.font_texture.em_pixels() as f32;
-        let char_pos_x = text_display.get_char_pos_x().into_iter().map(|&x| x * em_pixels).collect();
+/// Return the text displayed in place of a control or invisible character.
+fn placeholder(c: char) -> Option<String> {
+    match c {
+        '\t' | '\n' => None,
+        '\r' => Some("CR".into()),
+        '\u{0}' ..= '\u{1f}' | '\u{7f}' ..= '\u{9f}' | '\u{ad}' | '\u{200b}' | '\u{200e}' | '\u{200f}'
+        | '\u{2028}' ..= '\u{202e}' | '\u{2060}' ..= '\u{2064}' | '\u{feff}' => {
+            Some(format!("U+{:04X}", c as u32))
+        }, _ => None,
+    }
+}
 
-        LineRenderer {
-            text_display: text_display,
-            char_pos_x: char_pos_x,
-        }
+impl LineRenderer {
+    pub fn new(renderer: &Renderer, text: &str) -> LineRenderer {
+        LineRenderer::with_styles(renderer, text, &[], &[])
     }
 
-    pub fn draw(&self, target: &mut Target, px: f32, py: f32) {
-        let size = target.renderer.font_texture.em_pixels();
-        let (w, h) = target.target.get_dimensions();
-        let text_tf = |px: f32, py: f32| -> [[f32; 4]; 4] {
-            let (x, y) = (px / w as f32 * 2. - 1.,
-                         (py - size as f32 / 2.) / h as f32 * 2. - 1.);
+    /// Lay out a text of which the byte ranges `(start, end, style)` are drawn in the
+    /// given styles and the ranges `(start, end, color)` in the given colors. The rest
+    /// is drawn in the regular style and the color given to `draw`. Later ranges take
+    /// precedence.
+    pub fn with_styles(renderer: &Renderer, text: &str, styles: &[(u64, u64, FontStyle)],
+                       colors: &[(u64, u64, [f32; 4])]) -> LineRenderer {
+        // Control and invisible characters are replaced by placeholders in the displayed
+        // text, so the positions in the displayed text are mapped back to the original.
+        let mut display_text = String::with_capacity(text.len());
+        let mut display_ranges = Vec::with_capacity(text.len()); // (char, start, end) in display_text
+        let mut runs: Vec<(usize, FontStyle, Option<[f32; 4]>)> = vec![]; // (start, style, color) in display_text
+        for (i, c) in text.char_indices() {
+            let start = display_text.len();
+            // the CR of a CRLF line ending takes no space, like the LF
+            let replacement = if c == '\r' && text[i + 1..].starts_with('\n') { Some(String::new()) } else { placeholder(c) };
+            let (style, color) = match replacement {
+                Some(s) => {
+                    display_text.push_str(&s);
+                    (FontStyle::Regular, None)
+                },
+                None => {
+                    display_text.push(c);
+                    let i = i as u64;
+                    (styles.iter().rev().find(|&&(start, end, _)| start <= i && i < end)
+                         .map_or(FontStyle::Regular, |&(_, _, style)| style),
+                     colors.iter().rev().find(|&&(start, end, _)| start <= i && i < end)
+                         .map(|&(_, _, color)| color))
+                },
+            };
+            if runs.last().map_or(true, |&(_, last_style, last_color)| (last_style, last_color) != (style, color)) {
+                runs.push((start, style, color));
+            }
+            display_ranges.push((c, start, display_text.len()));
+        }
+        let runs: Vec<glium_text::TextRun> = runs.iter().enumerate().map(|(i, &(start, style, color))| {
+            let end = runs.get(i + 1).map_or(display_text.len(), |&(end, _, _)| end);
+            glium_text::TextRun {
+                text: &display_text[start..end],
+                style: style,
+                color: color.map(|c| (c[0], c[1], c[2], c[3])),
+            }
+        }).collect();
 
-            let scale = 2. * size as f32;
+        let mut text_display = glium_text::TextDisplay::new(&renderer.text_system, renderer.font_texture.clone(), "");
+        text_display.set_tab_width(renderer.config.tab_width);
+        text_display.set_runs(&runs);
+        let em_pixels = renderer.font_texture.em_pixels() as f32;
+        let display_pos_x: Vec<f32> = text_display.get_char_pos_x().into_iter().map(|&x| x * em_pixels).collect();
 
-            [[scale / w as f32, 0.0, 0.0, 0.0],
-             [0.0, scale / h as f32, 0.0, 0.0],
-             [0.0,              0.0, 1.0, 0.0],
-             [  x,                y, 0.0, 1.0]]
+        let mut line_renderer = LineRenderer {
+            text_display: text_display,
+            char_pos_x: Vec::with_capacity(text.len() + 1),
+            tabs: vec![],
+            spaces: vec![],
+            nb_spaces: vec![],
+            placeholders: vec![],
         };
-        glium_text::draw(&self.text_display, &target.renderer.text_system, &mut target.target, text_tf(px, py), (0., 0., 0., 1.));
+        line_renderer.char_pos_x.push(0.);
+        for (c, start, end) in display_ranges {
+            let range = (display_pos_x[start], display_pos_x[end]);
+            for _ in 0..c.len_utf8() {
+                line_renderer.char_pos_x.push(range.1);
+            }
+            match c {
+                '\t' => line_renderer.tabs.push(range),
+                ' ' => line_renderer.spaces.push(range),
+                '\u{a0}' | '\u{202f}' => line_renderer.nb_spaces.push(range),
+                c if end > start && end - start != c.len_utf8() => line_renderer.placeholders.push(range),
+                _ => (),
+            }
+        }
+        line_renderer
     }
-}
 
-#[derive(Copy, Clone)]
-pub struct Vertex {
-    pub position: [f32; 2],
-    pub color: [f32; 4],
-}
-implement_vertex!(Vertex, position, color);
+    /// Add the text, and the markers of whitespace and invisible characters, to the
+    /// batches of `target`. The text is vertically centered at `py`, and drawn in black
+    /// where it has no color of its own.
+    pub fn draw(&self, target: &mut Target, px: f32, py: f32, show_whitespace: bool) {
+        let size = target.renderer.font_texture.em_pixels() as f32;
+        target.renderer.text_batch.borrow_mut().push(&self.text_display, (px, py - size / 2.), size, (0., 0., 0., 1.));
 
-pub struct Primitive {
-    vertex_buffer: glium::VertexBuffer<Vertex>,
-    index_buffer:  glium::index::NoIndices,
-    fill: bool,
-}
+        let color = [0.7,0.7,0.7,1.];
+        let s = target.renderer.scale;
+        for &(x0, x1) in &self.placeholders {
+            target.rect_outline((px + x0, py - 8. * s), (px + x1, py + 8. * s), color);
+        }
 
-impl Primitive {
-    // pub fn new(renderer: &Renderer, verts: &[Vertex], primitive_type: glium::index::PrimitiveType, fill: bool) -> Self {
-    //     Primitive {
-    //         vertex_buffer: glium::VertexBuffer::new(&renderer.display, verts).unwrap(),
-    //         index_buffer: glium::index::NoIndices(primitive_type),
-    //         fill: fill,
-    //     }
-    // }
-
-    pub fn new_rect(renderer: &Renderer, p1: (f32,f32), p2: (f32,f32), color: [f32; 4]) -> Self {
-        let verts = vec![
-            Vertex { position: [p1.0, p1.1], color: color },
-            Vertex { position: [p2.0, p1.1], color: color },
-            Vertex { position: [p1.0, p2.1], color: color },
-            Vertex { position: [p2.0, p2.1], color: color },
-        ];
-        Primitive {
-            vertex_buffer: glium::VertexBuffer::new(&renderer.display, &verts).unwrap(),
-            index_buffer:  glium::index::NoIndices(PrimitiveType::TriangleStrip),
-            fill: true,
+        if show_whitespace || target.renderer.config.show_tabs {
+            self.draw_tab_arrows(target, px, py);
         }
-    }
 
-    pub fn new_line(renderer: &Renderer, p1: (f32,f32), p2: (f32,f32), color: [f32; 4]) -> Self {
-        let verts = vec![
-            Vertex { position: [p1.0, p1.1], color: color },
-            Vertex { position: [p2.0, p2.1], color: color },
-        ];
-        Primitive {
-            vertex_buffer: glium::VertexBuffer::new(&renderer.display, &verts).unwrap(),
-            index_buffer:  glium::index::NoIndices(PrimitiveType::LinesList),
-            fill: false,
+        if show_whitespace {
+            for &(x0, x1) in &self.spaces {
+                let x = px + ((x0 + x1) / 2.).round();
+                target.rect((x - s, py - 4. * s), (x + s, py - 2. * s), color);
+            }
+            for &(x0, x1) in &self.nb_spaces {
+                let x = px + ((x0 + x1) / 2.).round();
+                target.rect_outline((x - 2. * s, py - 5. * s), (x + 2. * s, py - s), color);
+            }
         }
     }
 
-    pub fn draw(&self, target: &mut Target, offset: (f32, f32)) -> Result<(), glium::DrawError> {
-        let (w, h) = target.target.get_dimensions();
-        let params = glium::DrawParameters {
-            polygon_mode: if self.fill { glium::draw_parameters::PolygonMode::Fill } else { glium::draw_parameters::PolygonMode::Line },
-            blend: glium::draw_parameters::Blend::alpha_blending(),
-            ..Default::default()
-        };
-        target.target.draw(&self.vertex_buffer, &self.index_buffer, &target.renderer.program, &uniform!{ win_size: (w as f32, h as f32), offset: offset }, &params)
+    // Draw an arrow across the width of every tab character.
+    fn draw_tab_arrows(&self, target: &mut Target, px: f32, py: f32) {
+        let color = [0.7,0.7,0.7,1.];
+        let s = target.renderer.scale;
+        for &(x0, x1) in &self.tabs {
+            let (x0, x1) = (x0 + 2. * s, x1 - 2. * s);
+            if x1 - x0 < 4. * s {
+                continue;
+            }
+            let (x0, x1) = (px + x0, px + x1);
+            target.line((x0, py), (x1, py), color);
+            target.line((x1 - 3. * s, py + 3. * s), (x1, py), color);
+            target.line((x1 - 3. * s, py - 3. * s), (x1, py), color);
+        }
     }
 }
+
+#[derive(Copy, Clone)]
+pub struct Vertex {
+    pub position: [f32; 2],
+    pub color: [f32; 4],
+}
+implement_vertex!(Vertex, position, color);
@@ -0,0 +1,70 @@
+use std::time::{Duration, Instant};
+
+use renderer::*;
+
+const MESSAGE_TIMEOUT_S: u64 = 5;
+
+/// Return the height of the status bar, which is one line of text.
+pub fn height(renderer: &Renderer) -> f32 {
+    renderer.line_height()
+}
+
+/// The bar at the bottom of the window, showing information about the buffer and
+/// transient messages.
+pub struct StatusBar {
+    text: String,
+    line_renderer: LineRenderer,
+    message: Option<(String, Instant)>,
+}
+
+impl StatusBar {
+    pub fn new(renderer: &Renderer) -> StatusBar {
+        StatusBar {
+            text: String::new(),
+            line_renderer: LineRenderer::new(renderer, ""),
+            message: None,
+        }
+    }
+
+    /// Show a message for a few seconds.
+    pub fn set_message(&mut self, message: String) {
+        self.message = Some((message, Instant::now()));
+    }
+
+    /// Return the current message, if it has not timed out yet.
+    pub fn message(&self) -> Option<&str> {
+        match self.message {
+            Some((ref message, time)) if time.elapsed() < Duration::from_secs(MESSAGE_TIMEOUT_S) => Some(message),
+            _ => None,
+        }
+    }
+
+    /// Return when the current message times out.
+    pub fn message_expiry(&self) -> Option<Instant> {
+        self.message.as_ref().map(|&(_, time)| time + Duration::from_secs(MESSAGE_TIMEOUT_S))
+            .filter(|&expiry| expiry > Instant::now())
+    }
+
+    /// Set the displayed text. The text is laid out again only if it changed.
+    pub fn set_text(&mut self, renderer: &Renderer, text: String) {
+        if text != self.text {
+            self.line_renderer = LineRenderer::new(renderer, &text);
+            self.text = text;
+        }
+    }
+
+    /// Lay out the text again after the font of the renderer changed.
+    pub fn relayout(&mut self, renderer: &Renderer) {
+        self.line_renderer = LineRenderer::new(renderer, &self.text);
+    }
+
+    pub fn render(&self, target: &mut Target) {
+        let w = target.get_dimensions().0 as f32;
+        let h = height(target.renderer);
+        target.rect((0., 0.), (w, h), [0.9,0.9,0.9,1.]);
+        target.line((0., h - 0.5), (w, h - 0.5), [0.7,0.7,0.7,1.]);
+        target.flush();
+        let x = 6. * target.renderer.scale();
+        self.line_renderer.draw(target, x, h / 2., false);
+    }
+}
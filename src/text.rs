@@ -1,117 +1,434 @@
 use std::collections::BTreeMap;
 use serde_json::Value;
+use glium_text::FontStyle;
 
 use renderer::*;
+use cursor::{Cursor, CursorShape};
+use unicode;
+use brackets::{self, Pos};
+use fold::{self, Folds, FoldMarker};
+use minimap::{Minimap, MinimapLine};
+use status_bar;
 
-const LINE_HEIGHT: f32 = 20.;
-const LEFT_MARGIN: f32 = 15.;
+const LEFT_MARGIN: f32 = 15.; // in logical pixels
+
+// flags of the "font" annotations of the core
+const FONT_BOLD: u64 = 1;
+const FONT_ITALIC: u64 = 4;
 
 // #[derive(Clone)]
-pub struct Line<'a> {
+pub struct Line {
     pub text: String,
-    pub cursor: Option<u64>,
-    selection: Option<(u64,u64)>,
-    pub renderer: LineRenderer<'a>, // This is the lifetime that infects the hierarchy up to State
+    pub cursors: Vec<u64>,
+    pub selections: Vec<(u64,u64)>,
+    pub finds: Vec<(u64,u64)>, // search hits
+    pub styles: Vec<(u64,u64,FontStyle)>, // bold and italic spans
+    pub colors: Vec<(u64,u64,[f32; 4])>, // spans with a foreground color
+    pub renderer: LineRenderer,
 }
 
-impl<'a> Line<'a> {
+impl Line {
 
-    pub fn placeholder(renderer: &'a Renderer) -> Line<'a> {
+    pub fn placeholder(renderer: &Renderer) -> Line {
         let text = ">>> NOT IN CACHE <<<";
         let renderer = LineRenderer::new(renderer, text);
-        Line { text: text.into(), cursor: None, selection: None, renderer: renderer }
+        Line { text: text.into(), cursors: vec![], selections: vec![], finds: vec![], styles: vec![], colors: vec![], renderer: renderer }
+    }
+
+    /// Return the x-position of a column relative to the start of the line. Columns are
+    /// byte offsets, see the `unicode` module.
+    pub fn column_x(&self, column: u64) -> f32 {
+        let ch_pos_x = &self.renderer.char_pos_x;
+        let column = unicode::prev_boundary(&self.text, column as usize);
+        ch_pos_x[::std::cmp::min(column, ch_pos_x.len() - 1)]
+    }
+
+    /// Return the x-position of the end of the grapheme at the given column.
+    pub fn next_column_x(&self, column: u64) -> f32 {
+        self.column_x(unicode::next_boundary(&self.text, column as usize) as u64)
+    }
+
+    /// Return the column closest to the x-position relative to the start of the line.
+    pub fn column_at_x(&self, x: f32) -> u64 {
+        unicode::boundary_at_x(&self.text, &self.renderer.char_pos_x, x) as u64
     }
 }
 
-pub struct Text<'a> {
-    cache: BTreeMap<u64, Line<'a>>,
-    placeholder_line: Line<'a>,
+pub struct Text {
+    cache: BTreeMap<u64, Line>,
+    minimap_cache: BTreeMap<u64, (u64, MinimapLine)>, // the lines shown in the minimap, not laid out, with the update they are from
+    updates: u64, // the number of updates from the core, after which the minimap lines are outdated
+    placeholder_line: Line,
     pub top: f64,
     pub height: f64,
     n_lines: u64,
     renderer: TextRenderer,
-    // scrollbar: Primitive,
+    pub cursor: Cursor,
+    pub show_whitespace: bool,
+    folds: Folds,
+    tab_width: u32,
+    show_minimap: bool,
+    window_size: (f32, f32),
+    scale: f32, // see `Renderer::scale`
+    line_height: f32,
+    left_margin: f32,
+    bottom_margin: f32, // the height of the status bar
 }
 
-impl<'a> Text<'a> {
+impl Text {
     pub fn new(renderer: &Renderer) -> Text {
         Text {
             cache: BTreeMap::new(),
+            minimap_cache: BTreeMap::new(),
+            updates: 0,
             placeholder_line: Line::placeholder(renderer),
             top: 0.,
             height: 0.,
             n_lines: 0,
-            renderer: TextRenderer::new(renderer, LEFT_MARGIN)
+            renderer: TextRenderer::new(LEFT_MARGIN * renderer.scale(), status_bar::height(renderer),
+                                        renderer.line_height()),
+            cursor: Cursor::new(renderer.config.cursor_shape, renderer.config.cursor_blink),
+            show_whitespace: renderer.config.show_whitespace,
+            folds: Folds::new(),
+            tab_width: renderer.config.tab_width,
+            show_minimap: renderer.config.minimap,
+            window_size: (0., 0.),
+            scale: renderer.scale(),
+            line_height: renderer.line_height(),
+            left_margin: LEFT_MARGIN * renderer.scale(),
+            bottom_margin: status_bar::height(renderer),
         }
     }
 
+    /// Lay out the cached lines again after the font or the scale of the renderer changed.
+    pub fn relayout(&mut self, renderer: &Renderer) {
+        for line in self.cache.values_mut() {
+            line.renderer = LineRenderer::with_styles(renderer, &line.text, &line.styles, &line.colors);
+        }
+        self.placeholder_line = Line::placeholder(renderer);
+        self.scale = renderer.scale();
+        self.line_height = renderer.line_height();
+        self.left_margin = LEFT_MARGIN * self.scale;
+        self.bottom_margin = status_bar::height(renderer);
+        self.renderer = TextRenderer::new(self.left_margin, self.bottom_margin, self.line_height);
+        let (w, h) = self.window_size;
+        self.set_size(w as u32, h as u32);
+        self.scroll(0.); // keep the view within the text
+    }
+
     pub fn refresh(&mut self, n_lines: u64) {
+        // lines are inserted or removed at the first cursor or selection
+        if n_lines != self.n_lines {
+            let delta = n_lines as i64 - self.n_lines as i64;
+            match self.edit_position() {
+                Some(pos) => self.folds.shift(pos, delta),
+                None => self.folds.clear(),
+            }
+        }
         self.cache.clear();
+        // the outdated minimap lines are drawn until they are fetched again
+        self.updates += 1;
         self.n_lines = n_lines;
     }
 
+    /// Forget the folds and the minimap lines when the buffer is replaced by another file,
+    /// or by the same file loaded again.
+    pub fn reset_folds(&mut self) {
+        self.folds.clear();
+        self.minimap_cache.clear();
+    }
+
+    /// Return the first position of the cursors and selections in the cache.
+    fn edit_position(&self) -> Option<Pos> {
+        self.cache.iter().filter_map(|(&n, line)| {
+            line.cursors.iter().cloned()
+                .chain(line.selections.iter().map(|&(start, _)| start))
+                .min()
+                .map(|column| (n, column))
+        }).next()
+    }
+
     pub fn render(&self, target: &mut Target) {
-        self.renderer.draw(target, &self.get_lines(), self.top, self.height, self.n_lines);
+        self.renderer.draw(target, &self.get_lines(), &self.cursor, self.show_whitespace,
+                           self.matching_brackets(), self.top, self.height, self.view_line_count());
+
+        if let Some(minimap) = self.minimap() {
+            let (first, last) = minimap.view_range();
+            let lines: Vec<(u64, &MinimapLine)> = (first .. last)
+                .filter_map(|v| self.minimap_cache.get(&self.folds.view_to_buffer(v)).map(|&(_, ref line)| (v, line)))
+                .collect();
+            minimap.draw(target, &lines, self.top, self.height, self.tab_width);
+        }
+    }
+
+    fn minimap(&self) -> Option<Minimap> {
+        if self.show_minimap {
+            Some(Minimap::new(self.window_size, self.scale, self.bottom_margin, self.top, self.height, self.view_line_count()))
+        } else {
+            None
+        }
+    }
+
+    /// Return true if the x-coordinate is over the minimap.
+    pub fn minimap_contains(&self, px: i32) -> bool {
+        self.minimap().map_or(false, |minimap| minimap.contains(px))
+    }
+
+    /// Scroll to center the line shown in the minimap at `py`, measured from the top.
+    pub fn scroll_to_minimap(&mut self, py: i32) {
+        if let Some(minimap) = self.minimap() {
+            let line = minimap.view_line_at(py);
+            let delta = line - self.height / 2. - self.top;
+            self.scroll(delta);
+        }
     }
 
-    pub fn add_lines(&mut self, renderer: &'a Renderer, value: &Value, first: u64) {
+    /// Return the text of a cached line.
+    fn get_text(&self, n: u64) -> Option<&str> {
+        self.cache.get(&n).map(|line| line.text.as_str())
+    }
+
+    /// Fold the region at the first cursor. Return true if something was folded.
+    pub fn fold_at_cursor(&mut self) -> bool {
+        match self.cursor_position() {
+            Some((line, _)) => self.fold(line),
+            None => false,
+        }
+    }
+
+    /// Unfold the region at the first cursor. Return true if something was unfolded.
+    pub fn unfold_at_cursor(&mut self) -> bool {
+        match self.cursor_position() {
+            Some((line, _)) => self.folds.unfold(line),
+            None => false,
+        }
+    }
+
+    /// Fold or unfold the region starting at the given line.
+    pub fn toggle_fold(&mut self, line: u64) -> bool {
+        if self.folds.is_folded(line) {
+            self.folds.unfold(line)
+        } else {
+            self.fold(line)
+        }
+    }
+
+    fn fold(&mut self, line: u64) -> bool {
+        match fold::region_at(|n| self.get_text(n), line, self.tab_width) {
+            Some(region) => {
+                self.folds.fold(region);
+                true
+            }, None => false,
+        }
+    }
+
+    fn fold_marker(&self, line: u64) -> FoldMarker {
+        if self.folds.is_folded(line) {
+            FoldMarker::Folded
+        } else if fold::is_foldable(|n| self.get_text(n), line, self.tab_width) {
+            FoldMarker::Foldable
+        } else {
+            FoldMarker::None
+        }
+    }
+
+    /// The number of lines on the screen, with folded regions collapsed.
+    fn view_line_count(&self) -> u64 {
+        self.folds.view_line_count(self.n_lines)
+    }
+
+    /// Return the range of buffer lines which are visible, to be sent to `Core::scroll`.
+    pub fn scroll_range(&self) -> (u64, u64) {
+        let (first, last) = (self.top as u64, (self.top + self.height).ceil() as u64);
+        (self.folds.view_to_buffer(first), self.folds.view_to_buffer(last))
+    }
+
+    /// Return the range of buffer lines, `first .. last`, which covers the lines shown in
+    /// the minimap which are not known or outdated, to be fetched with `Core::render_lines`.
+    /// The visible lines come with the updates.
+    pub fn missing_minimap_range(&self) -> Option<(u64, u64)> {
+        let minimap = match self.minimap() { Some(minimap) => minimap, None => return None };
+        let (first, last) = minimap.view_range();
+        let last = ::std::cmp::min(last, self.view_line_count());
+        let mut missing = (first .. last)
+            .map(|v| self.folds.view_to_buffer(v))
+            .filter(|n| self.minimap_cache.get(n).map_or(true, |&(update, _)| update != self.updates));
+        missing.next().map(|first| (first, missing.last().unwrap_or(first) + 1))
+    }
+
+    /// Return the number of updates so far, to be given to `add_minimap_lines` with the
+    /// lines requested now.
+    pub fn updates(&self) -> u64 {
+        self.updates
+    }
+
+    /// Add lines for the minimap, returned by `Core::render_lines` from `first` on and
+    /// requested after the given number of updates.
+    pub fn add_minimap_lines(&mut self, value: &Value, first: u64, updates: u64) {
+        for (i, line) in value.as_array().unwrap().into_iter().enumerate() {
+            let line = line.as_array().unwrap();
+            let spans = |kind: &str| -> Vec<(u64, u64)> {
+                line.iter().skip(1).map(|a| a.as_array().unwrap())
+                    .filter(|a| a[0].as_str() == Some(kind))
+                    .map(|a| (a[1].as_u64().unwrap(), a[2].as_u64().unwrap()))
+                    .collect()
+            };
+            self.minimap_cache.insert(i as u64 + first, (updates, MinimapLine {
+                text: line[0].as_str().unwrap().to_string(),
+                finds: !spans("find").is_empty(),
+                selection: spans("sel").iter().any(|&(a, b)| a != b),
+            }));
+        }
+    }
+
+    /// Return true if the x-coordinate is in the gutter, left of the text.
+    pub fn in_gutter(&self, px: i32) -> bool {
+        (px as f32) < self.left_margin
+    }
+
+    /// Return the position of the first cursor in the cache.
+    pub fn cursor_position(&self) -> Option<Pos> {
+        self.cache.iter()
+            .filter_map(|(&n, line)| line.cursors.first().map(|&column| (n, column)))
+            .next()
+    }
+
+    /// Return the position of the first cursor as shown to the user: the line and the
+    /// column in grapheme clusters, both counted from 1.
+    pub fn cursor_display_position(&self) -> Option<(u64, u64)> {
+        self.cursor_position().and_then(|(n, column)| {
+            self.get_text(n).map(|text| (n + 1, unicode::grapheme_column(text, column as usize) as u64 + 1))
+        })
+    }
+
+    /// Return the line ending of the cached lines, "CRLF" or "LF".
+    pub fn line_ending(&self) -> &'static str {
+        if self.cache.values().any(|line| line.text.ends_with("\r\n")) { "CRLF" } else { "LF" }
+    }
+
+    /// Return the bracket pair at or around the first cursor.
+    pub fn matching_brackets(&self) -> Option<(Pos, Pos)> {
+        self.cursor_position().and_then(|pos| {
+            brackets::find_pair(|n| self.get_text(n), pos)
+        })
+    }
+
+    /// Return the position the cursor should jump to, to get to the matching bracket.
+    pub fn matching_bracket_target(&self) -> Option<Pos> {
+        match (self.cursor_position(), self.matching_brackets()) {
+            (Some((line, column)), Some((start, end))) => {
+                // from the opening bracket or inside the pair, jump to the closing one
+                if start.0 == line && (start.1 == column || start.1 + 1 == column) {
+                    Some(end)
+                } else if end.0 == line && (end.1 == column || end.1 + 1 == column) {
+                    Some(start)
+                } else {
+                    Some(end)
+                }
+            }, _ => None,
+        }
+    }
+
+    pub fn add_lines(&mut self, renderer: &Renderer, value: &Value, first: u64) {
         for (i, line) in value.as_array().unwrap().into_iter().enumerate() {
             let line = line.as_array().unwrap();
             let text = line[0].as_str().unwrap().to_string();
             // annotations
-            let mut cursor = None;
-            let mut selection = None;
+            let mut cursors = vec![];
+            let mut selections = vec![];
+            let mut finds = vec![];
+            let mut styles = vec![];
+            let mut colors = vec![];
             for annotation in line.iter().skip(1).map(|a| a.as_array().unwrap()) {
                 match annotation[0].as_str().unwrap() {
                     "cursor" => {
-                        cursor = Some(annotation[1].as_u64().unwrap());
+                        cursors.push(annotation[1].as_u64().unwrap());
                     },
                     "sel" => {
-                        selection = Some((annotation[1].as_u64().unwrap(), annotation[2].as_u64().unwrap()));
+                        selections.push((annotation[1].as_u64().unwrap(), annotation[2].as_u64().unwrap()));
+                    },
+                    "find" => {
+                        finds.push((annotation[1].as_u64().unwrap(), annotation[2].as_u64().unwrap()));
+                    },
+                    "fg" => {
+                        let argb = annotation[3].as_u64().unwrap();
+                        let channel = |shift: u64| ((argb >> shift) & 0xff) as f32 / 255.;
+                        colors.push((annotation[1].as_u64().unwrap(), annotation[2].as_u64().unwrap(),
+                                     [channel(16), channel(8), channel(0), channel(24)]));
+                    },
+                    "font" => {
+                        let flags = annotation[3].as_u64().unwrap();
+                        let style = FontStyle::new(flags & FONT_BOLD != 0, flags & FONT_ITALIC != 0);
+                        if style != FontStyle::Regular {
+                            styles.push((annotation[1].as_u64().unwrap(), annotation[2].as_u64().unwrap(), style));
+                        }
                     }, _ => () // ignore unknown annotations
                 }
             }
-            let renderer = LineRenderer::new(renderer, &text);
-            self.cache.insert(i as u64+first, Line { text: text, cursor: cursor, selection: selection, renderer: renderer });
+            self.minimap_cache.insert(i as u64 + first, (self.updates, MinimapLine {
+                text: text.clone(),
+                finds: !finds.is_empty(),
+                selection: selections.iter().any(|&(a, b)| a != b),
+            }));
+            let renderer = LineRenderer::with_styles(renderer, &text, &styles, &colors);
+            self.cache.insert(i as u64+first, Line {
+                text: text, cursors: cursors, selections: selections, finds: finds, styles: styles, colors: colors,
+                renderer: renderer,
+            });
         }
     }
 
     pub fn scroll_to(&mut self, line: u64, _column: u64) {
         let min = |a,b| if a > b { b } else { a };
         let max = |a,b| if a < b { b } else { a };
+        let line = self.folds.buffer_to_view(line);
         self.top = max(0., min(self.top, line as f64 - 2.)); // scroll up
-        self.top = min(self.n_lines as f64, max(self.top, line as f64 - self.height + 1. + 2.)); // scroll dn
+        self.top = min(self.view_line_count() as f64, max(self.top, line as f64 - self.height + 1. + 2.)); // scroll dn
     }
 
     pub fn scroll(&mut self, delta_y: f64) {
         let mut y = self.top + delta_y;
-        let max = self.n_lines as f64 - self.height;
+        let max = self.view_line_count() as f64 - self.height;
         if y > max { y = max }
         if y < 0.  { y = 0. }
         self.top = y;
     }
 
-    pub fn get_lines(&self) -> Vec<(f32, &Line)> {
-        self.get_line_pos().into_iter().filter_map(|(pos,i)| self.get_line(i).map(|x| (pos,x))).collect()
+    // Return: Vec<(line_pos, line_id, line, fold_marker)>
+    pub fn get_lines(&self) -> Vec<(f32, u64, &Line, FoldMarker)> {
+        self.get_line_pos().into_iter()
+            .filter_map(|(pos,i)| self.get_line(i).map(|x| (pos,i,x,self.fold_marker(i))))
+            .collect()
     }
 
-    // Return: Vec<(line_pos, line_id)>
+    // Return: Vec<(line_pos, line_id)>, line_id being the buffer line
     pub fn get_line_pos(&self) -> Vec<(f32, u64)> {
         (self.top as u64 .. (self.top + self.height).ceil() as u64)
-            .map(|i| ((self.height - i as f64 + self.top - 0.5) as f32 * LINE_HEIGHT, i)
+            .map(|i| ((self.height - i as f64 + self.top - 0.5) as f32 * self.line_height + self.bottom_margin, self.folds.view_to_buffer(i))
             ).collect()
     }
 
     pub fn get_line_col(&self, px: i32, py: i32) -> (u64,u64) {
         let line = self.get_line_pos().into_iter().min_by_key(|&(y,_)| (y as i32 - py).abs()).unwrap().1;
-        let column = if let Some(line) = self.get_line(line) {
-            line.renderer.char_pos_x.iter().enumerate().min_by_key(|&(_,x)| {
-                (*x as i32 - px + LEFT_MARGIN as i32).abs()
-            }).unwrap().0 as u64
+        (line, self.get_column(line, px))
+    }
+
+    /// Return the column closest to the x-coordinate `px` on the given line.
+    pub fn get_column(&self, line: u64, px: i32) -> u64 {
+        if let Some(line) = self.get_line(line) {
+            line.column_at_x(px as f32 - self.left_margin)
         } else { // after the text
             0
-        };
-        (line, column)
+        }
+    }
+
+    /// Return the (line, start column, end column) ranges of a rectangular selection
+    /// spanning lines `line_a` to `line_b` and x-coordinates `px_a` to `px_b`.
+    pub fn get_column_ranges(&self, (line_a, px_a): (u64, i32), (line_b, px_b): (u64, i32)) -> Vec<(u64,u64,u64)> {
+        let (first, last) = if line_a <= line_b { (line_a, line_b) } else { (line_b, line_a) };
+        (first .. last + 1).filter(|&line| !self.folds.is_hidden(line)).map(|line| {
+            (line, self.get_column(line, px_a), self.get_column(line, px_b))
+        }).collect()
     }
 
     fn get_line(&self, n: u64) -> Option<&Line> {
@@ -122,59 +439,156 @@ impl<'a> Text<'a> {
         }
     }
 
-    pub fn set_size(&mut self, _w: u32, h: u32) {
-        self.height = h as f64 / LINE_HEIGHT as f64;
+    pub fn set_size(&mut self, w: u32, h: u32) {
+        self.height = (h as f64 - self.bottom_margin as f64) / self.line_height as f64;
+        self.window_size = (w as f32, h as f32);
     }
 }
 
 pub struct TextRenderer {
-    cursor: Primitive,
-    line_bg: Primitive,
     left_margin: f32,
+    bottom_margin: f32,
+    line_height: f32,
 }
 
 impl TextRenderer {
-    pub fn new(renderer: &Renderer, left_margin: f32) -> TextRenderer {
-        let cursor = Primitive::new_line(&renderer, (0.,-10.), (0.,10.), [0.,0.,0.,1.]);
-        let line_bg = Primitive::new_rect(&renderer, (0., -10.), (2000., 10.), [1.,1.,0.7,1.]);
+    pub fn new(left_margin: f32, bottom_margin: f32, line_height: f32) -> TextRenderer {
+        TextRenderer { left_margin: left_margin, bottom_margin: bottom_margin, line_height: line_height }
+    }
+
+    /// Add the backgrounds of the cursor line, search hits and selections to the batch.
+    fn draw_line_background(&self, target: &mut Target, line: &Line, (px, py): (f32, f32)) {
+        let half = self.line_height / 2.;
+        if !line.cursors.is_empty() {
+            let w = target.get_dimensions().0 as f32;
+            target.rect((px, py - half), (w, py + half), [1.,1.,0.7,1.]);
+        }
 
-        TextRenderer { cursor: cursor, line_bg: line_bg, left_margin: left_margin }
+        for find in &line.finds {
+            target.rect((line.column_x(find.0) + px, py - half), (line.column_x(find.1) + px, py + half),
+                        [1.,0.85,0.4,1.]);
+        }
+
+        for sel in &line.selections {
+            target.rect((line.column_x(sel.0) + px, py - half), (line.column_x(sel.1) + px, py + half),
+                        [0.5,0.5,1.,1.]);
+        }
     }
 
-    pub fn draw_line(&self, target: &mut Target, line: &Line, (px, py): (f32, f32)) {
-        let offset = |pos| {
-            let ch_pos_x = &line.renderer.char_pos_x;
-            ch_pos_x[::std::cmp::min(pos as usize, ch_pos_x.len() - 1)]
-        };
+    /// Add the decorations drawn over the text of a line to the batch.
+    fn draw_line_overlay(&self, target: &mut Target, line: &Line, cursor: &Cursor,
+                         brackets: &[u64], fold_marker: FoldMarker, (px, py): (f32, f32)) {
+        let half = self.line_height / 2.;
+        for &column in brackets {
+            target.rect_outline((px + line.column_x(column), py - half + 1.), (px + line.next_column_x(column), py + half - 1.),
+                                [0.,0.,0.,0.6]);
+        }
+
+        self.draw_fold_marker(target, line, fold_marker, (px, py));
 
-        if let Some(pos) = line.cursor {
-            self.line_bg.draw(target, (px, py)).unwrap();
-            self.cursor.draw(target, (offset(pos) + px, py)).unwrap();
+        if cursor.is_visible() {
+            for &pos in &line.cursors {
+                self.draw_cursor(target, line, cursor, pos, (px, py));
+            }
         }
+    }
 
-        if let Some(sel) = line.selection {
-            let selection_bg = Primitive::new_rect(&target.renderer,
-                (offset(sel.0) as f32 + px, -10.),
-                (offset(sel.1) as f32 + px, 10.),
-                [0.5,0.5,1.,1.]);
-            selection_bg.draw(target, (0.,py)).unwrap();
+    fn draw_fold_marker(&self, target: &mut Target, line: &Line, fold_marker: FoldMarker, (px, py): (f32, f32)) {
+        let color = [0.5,0.5,0.5,1.];
+        let s = target.renderer.scale();
+        let mut lines = vec![]; // in logical pixels
+        match fold_marker {
+            FoldMarker::None => (),
+            FoldMarker::Foldable => { // a "v" in the gutter
+                lines.push(((-12., 2.), (-8., -2.)));
+                lines.push(((-8., -2.), (-4., 2.)));
+            },
+            FoldMarker::Folded => { // a ">" in the gutter and a box with an ellipsis after the line
+                lines.push(((-10., 4.), (-6., 0.)));
+                lines.push(((-6., 0.), (-10., -4.)));
+                let x = px + line.renderer.char_pos_x.last().cloned().unwrap_or(0.) + 4. * s;
+                target.rect_outline((x, py - 6. * s), (x + 16. * s, py + 6. * s), color);
+                for i in 0..3 {
+                    let dot_x = x + (4. + 4. * i as f32) * s;
+                    target.rect((dot_x - s, py - s), (dot_x + s, py + s), color);
+                }
+            },
         }
+        for ((x1, y1), (x2, y2)) in lines {
+            target.line((px + x1 * s, py + y1 * s), (px + x2 * s, py + y2 * s), color);
+        }
+    }
 
-        line.renderer.draw(target, px, py);
+    fn draw_cursor(&self, target: &mut Target, line: &Line, cursor: &Cursor, pos: u64, (px, py): (f32, f32)) {
+        // The cursor covers the glyph under it. At the end of the line or on a zero-width
+        // glyph, it is half an em wide.
+        let x0 = px + line.column_x(pos);
+        let x1 = match px + line.next_column_x(pos) {
+            x1 if x1 > x0 => x1,
+            _ => x0 + target.renderer.em_pixels() as f32 / 2.,
+        };
+
+        let color = [0.,0.,0.,1.];
+        let (half, s) = (self.line_height / 2., target.renderer.scale());
+        if cursor.is_hollow() {
+            target.rect_outline((x0, py - half), (x1, py + half), color);
+        } else {
+            match cursor.shape {
+                CursorShape::Block => target.rect((x0, py - half), (x1, py + half), [0.,0.,0.,0.4]),
+                CursorShape::Bar => target.rect((x0 - s, py - half), (x0 + s, py + half), color),
+                CursorShape::Underline => target.rect((x0, py - half), (x1, py - half + 2. * s), color),
+            }
+        }
     }
 
-    pub fn draw(&self, target: &mut Target, lines: &[(f32,&Line)], top: f64, height: f64, n_lines: u64) {
-        for &(y, line) in lines {
-            self.draw_line(target, &line, (self.left_margin, y));
+    /// Draw the lines in three passes: the backgrounds, the text, and the decorations
+    /// over the text, which are left in the batch of `target`. Each pass is flushed
+    /// before the next one.
+    pub fn draw(&self, target: &mut Target, lines: &[(f32,u64,&Line,FoldMarker)], cursor: &Cursor, show_whitespace: bool,
+                brackets: Option<(Pos, Pos)>, top: f64, height: f64, n_lines: u64) {
+        for &(y, _, line, _) in lines {
+            self.draw_line_background(target, &line, (self.left_margin, y));
+        }
+        target.flush();
+
+        for &(y, _, line, _) in lines {
+            line.renderer.draw(target, self.left_margin, y, show_whitespace);
+        }
+        target.flush();
+
+        let column_width = target.renderer.column_width();
+        let line_limit_x = target.renderer.config.line_limit.map(|limit| limit as f32 * column_width);
+        let half = self.line_height / 2.;
+        for &(y, n, line, fold_marker) in lines {
+            let bracket_columns: Vec<u64> = brackets.iter()
+                .flat_map(|&(start, end)| vec![start, end])
+                .filter(|&(bracket_line, _)| bracket_line == n)
+                .map(|(_, column)| column)
+                .collect();
+            self.draw_line_overlay(target, &line, cursor, &bracket_columns, fold_marker, (self.left_margin, y));
+
+            // shade the part of the line over the limit
+            if let (Some(limit_x), Some(&end_x)) = (line_limit_x, line.renderer.char_pos_x.last()) {
+                if end_x > limit_x {
+                    target.rect((self.left_margin + limit_x, y - half), (self.left_margin + end_x, y + half), [1.,0.,0.,0.15]);
+                }
+            }
         }
 
-        // draw scrollbar
         let dims = target.get_dimensions();
         let (w, h) = (dims.0 as f32, dims.1 as f32);
+
+        // draw rulers
+        let renderer = target.renderer;
+        for &column in &renderer.config.rulers {
+            let x = (self.left_margin + column as f32 * column_width).round() + 0.5;
+            target.line((x, self.bottom_margin), (x, h), [0.,0.,0.,0.15]);
+        }
+
+        // draw scrollbar
         let (rel_y, rel_h) = (top / n_lines as f64, height / n_lines as f64);
-        let scrollbar = Primitive::new_rect(&target.renderer,
-            (w - 20., h - rel_y as f32 * h), (w, h - (rel_y + rel_h) as f32 * h),
-            [0.5,0.5,0.5,1.]);
-        scrollbar.draw(target, (0.,0.)).unwrap();
+        let track = h - self.bottom_margin;
+        let scrollbar_width = 20. * renderer.scale();
+        target.rect((w - scrollbar_width, h - rel_y as f32 * track), (w, h - (rel_y + rel_h) as f32 * track), [0.5,0.5,0.5,1.]);
     }
 }
@@ -0,0 +1,196 @@
+// Mapping between the column offsets exchanged with the core and positions in a line.
+//
+// Columns are UTF-8 byte offsets into the line text, both in the annotations sent by the
+// core and in the clicks and drags we send back. `glium_text` reports glyph positions by
+// byte offset too, so a column indexes `char_pos_x` directly. The cursor must never land
+// inside a user-perceived character, so hit-testing snaps to grapheme cluster boundaries.
+// Conversions to and from UTF-16 offsets are provided for platform APIs that count in
+// UTF-16 code units.
+
+/// Return true if `c` never starts a grapheme cluster, but extends the preceding one.
+fn is_extending(c: char) -> bool {
+    match c as u32 {
+        0x0300 ..= 0x036F | // combining diacritical marks
+        0x0483 ..= 0x0489 | // cyrillic
+        0x0591 ..= 0x05BD | // hebrew
+        0x0610 ..= 0x061A | 0x064B ..= 0x065F | 0x0670 | 0x06D6 ..= 0x06DC | 0x06DF ..= 0x06E4 | // arabic
+        0x0900 ..= 0x0903 | 0x093A ..= 0x094F | 0x0951 ..= 0x0957 | 0x0962 ..= 0x0963 | // devanagari
+        0x0E31 | 0x0E34 ..= 0x0E3A | 0x0E47 ..= 0x0E4E | // thai
+        0x1AB0 ..= 0x1AFF | // combining diacritical marks extended
+        0x1DC0 ..= 0x1DFF | // combining diacritical marks supplement
+        0x200C ..= 0x200D | // zero width non-joiner and joiner
+        0x20D0 ..= 0x20FF | // combining marks for symbols
+        0x302A ..= 0x302F | 0x3099 ..= 0x309A | // CJK and kana marks
+        0xFE00 ..= 0xFE0F | // variation selectors
+        0xFE20 ..= 0xFE2F | // combining half marks
+        0x1F3FB ..= 0x1F3FF | // emoji skin tone modifiers
+        0xE0020 ..= 0xE007F | // tags
+        0xE0100 ..= 0xE01EF  // variation selectors supplement
+            => true,
+        _ => false,
+    }
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    match c as u32 {
+        0x1F1E6 ..= 0x1F1FF => true,
+        _ => false,
+    }
+}
+
+/// Return the byte offsets of the grapheme cluster boundaries in `text`, including 0 and
+/// `text.len()`.
+///
+/// This is a simplified version of the Unicode segmentation rules, which handles
+/// combining marks, variation selectors, emoji modifiers, emoji ZWJ sequences, flags and
+/// CR LF.
+pub fn grapheme_boundaries(text: &str) -> Vec<usize> {
+    let mut boundaries = vec![0];
+    let mut prev: Option<char> = None;
+    let mut regional_indicators = 0;
+    for (i, c) in text.char_indices() {
+        let joined = match prev {
+            None => true, // the start of the text is already a boundary
+            Some(prev) => {
+                is_extending(c)
+                    || (prev == '\u{200D}' && !is_extending(c))
+                    || (prev == '\r' && c == '\n')
+                    || (is_regional_indicator(c) && regional_indicators % 2 == 1)
+            }
+        };
+        if !joined {
+            boundaries.push(i);
+        }
+        regional_indicators = if is_regional_indicator(c) { regional_indicators + 1 } else { 0 };
+        prev = Some(c);
+    }
+    if text.len() > 0 {
+        boundaries.push(text.len());
+    }
+    boundaries
+}
+
+/// Return the grapheme cluster boundary at or before the byte offset `offset`.
+pub fn prev_boundary(text: &str, offset: usize) -> usize {
+    grapheme_boundaries(text).into_iter().take_while(|&b| b <= offset).last().unwrap_or(0)
+}
+
+/// Return the grapheme cluster boundary after the byte offset `offset`, or `text.len()`.
+pub fn next_boundary(text: &str, offset: usize) -> usize {
+    grapheme_boundaries(text).into_iter().find(|&b| b > offset).unwrap_or(text.len())
+}
+
+/// Return the grapheme cluster boundary closest to the x-coordinate `x`, given the
+/// x-positions of the characters by byte offset (as in `LineRenderer::char_pos_x`).
+pub fn boundary_at_x(text: &str, char_pos_x: &[f32], x: f32) -> usize {
+    grapheme_boundaries(text).into_iter()
+        .filter(|&b| b < char_pos_x.len())
+        .min_by_key(|&b| ((char_pos_x[b] - x).abs() * 1024.) as i64)
+        .unwrap_or(0)
+}
+
+/// Return the number of grapheme clusters before the byte offset `offset`. This is the
+/// column as the user sees it.
+pub fn grapheme_column(text: &str, offset: usize) -> usize {
+    grapheme_boundaries(text).into_iter().take_while(|&b| b < offset).count()
+}
+
+/// Convert a byte offset to an offset in UTF-16 code units.
+#[allow(dead_code)]
+pub fn byte_to_utf16(text: &str, offset: usize) -> usize {
+    text.char_indices().take_while(|&(i, _)| i < offset).map(|(_, c)| c.len_utf16()).sum()
+}
+
+/// Convert an offset in UTF-16 code units to a byte offset. An offset inside a surrogate
+/// pair is rounded down to the start of the character.
+#[allow(dead_code)]
+pub fn utf16_to_byte(text: &str, offset: usize) -> usize {
+    let mut utf16 = 0;
+    for (i, c) in text.char_indices() {
+        utf16 += c.len_utf16();
+        if utf16 > offset {
+            return i;
+        }
+    }
+    text.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii_boundaries() {
+        assert_eq!(grapheme_boundaries(""), vec![0]);
+        assert_eq!(grapheme_boundaries("abc"), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn cjk_boundaries() {
+        // every CJK character is 3 bytes long in UTF-8
+        let text = "a漢字b";
+        assert_eq!(grapheme_boundaries(text), vec![0, 1, 4, 7, 8]);
+        assert_eq!(prev_boundary(text, 5), 4);
+        assert_eq!(next_boundary(text, 1), 4);
+        assert_eq!(grapheme_column(text, 7), 3);
+    }
+
+    #[test]
+    fn combining_mark_boundaries() {
+        // "e" followed by a combining acute accent is a single grapheme
+        let text = "e\u{301}x";
+        assert_eq!(grapheme_boundaries(text), vec![0, 3, 4]);
+        assert_eq!(prev_boundary(text, 1), 0);
+        assert_eq!(next_boundary(text, 0), 3);
+        assert_eq!(grapheme_column(text, 3), 1);
+    }
+
+    #[test]
+    fn emoji_boundaries() {
+        // a single emoji is 4 bytes long
+        assert_eq!(grapheme_boundaries("😀a"), vec![0, 4, 5]);
+        // thumbs up with a skin tone modifier
+        assert_eq!(grapheme_boundaries("👍🏽"), vec![0, 8]);
+        // family: man ZWJ woman ZWJ girl
+        assert_eq!(grapheme_boundaries("👨\u{200D}👩\u{200D}👧!"), vec![0, 18, 19]);
+        // two flags
+        assert_eq!(grapheme_boundaries("🇨🇿🇬🇧"), vec![0, 8, 16]);
+        // heart with an emoji variation selector
+        assert_eq!(grapheme_boundaries("❤\u{FE0F}"), vec![0, 6]);
+    }
+
+    #[test]
+    fn crlf_boundaries() {
+        assert_eq!(grapheme_boundaries("a\r\n"), vec![0, 1, 3]);
+    }
+
+    #[test]
+    fn utf16_offsets() {
+        let text = "a漢😀e\u{301}";
+        assert_eq!(byte_to_utf16(text, 0), 0);
+        assert_eq!(byte_to_utf16(text, 1), 1);
+        assert_eq!(byte_to_utf16(text, 4), 2);
+        assert_eq!(byte_to_utf16(text, 8), 4); // the emoji is a surrogate pair
+        assert_eq!(byte_to_utf16(text, text.len()), 6);
+
+        assert_eq!(utf16_to_byte(text, 2), 4);
+        assert_eq!(utf16_to_byte(text, 3), 4); // inside the surrogate pair
+        assert_eq!(utf16_to_byte(text, 4), 8);
+        assert_eq!(utf16_to_byte(text, 6), text.len());
+        assert_eq!(utf16_to_byte(text, 100), text.len());
+    }
+
+    #[test]
+    fn hit_testing() {
+        // x-positions by byte offset, as reported by glium_text: every byte of a character
+        // is positioned at the end of that character.
+        let text = "a漢e\u{301}";
+        let char_pos_x = [0., 10., 30., 30., 30., 40., 40., 40.];
+        assert_eq!(boundary_at_x(text, &char_pos_x, -5.), 0);
+        assert_eq!(boundary_at_x(text, &char_pos_x, 12.), 1);
+        assert_eq!(boundary_at_x(text, &char_pos_x, 25.), 4);
+        // a click on the combining mark goes before or after the whole grapheme
+        assert_eq!(boundary_at_x(text, &char_pos_x, 36.), 7);
+        assert_eq!(boundary_at_x(text, &char_pos_x, 100.), 7);
+    }
+}
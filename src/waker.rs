@@ -0,0 +1,69 @@
+// Waking the main loop up.
+//
+// The main loop sleeps until the window gets an event. Threads which send something to
+// the main loop (the core, the dialogs and the file watcher) wake it up with an
+// `Awakened` event, and so does the `Timer` when something is due, like a cursor blink.
+
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Instant;
+
+use glium::glutin::WindowProxy;
+
+#[derive(Clone)]
+pub struct Waker {
+    proxy: Option<WindowProxy>,
+}
+
+impl Waker {
+    /// Create a waker for the event loop of a window, or one which does nothing.
+    pub fn new(proxy: Option<WindowProxy>) -> Waker {
+        Waker { proxy: proxy }
+    }
+
+    pub fn wake(&self) {
+        if let Some(ref proxy) = self.proxy {
+            proxy.wakeup_event_loop();
+        }
+    }
+}
+
+/// Wakes the main loop up at a given time.
+pub struct Timer {
+    tx: mpsc::Sender<Option<Instant>>,
+}
+
+impl Timer {
+    pub fn new(waker: Waker) -> Timer {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut deadline: Option<Instant> = None;
+            loop {
+                let received = match deadline {
+                    Some(d) => {
+                        let now = Instant::now();
+                        if d <= now {
+                            waker.wake();
+                            deadline = None;
+                            continue;
+                        }
+                        rx.recv_timeout(d - now)
+                    },
+                    None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match received {
+                    Ok(d) => deadline = d,
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        });
+        Timer { tx: tx }
+    }
+
+    /// Wake the main loop up at `deadline`, replacing the previous deadline. `None`
+    /// cancels it.
+    pub fn wake_at(&self, deadline: Option<Instant>) {
+        self.tx.send(deadline).unwrap();
+    }
+}
@@ -0,0 +1,171 @@
+// Watching the open file for changes made by other programs.
+//
+// Editors and tools like git often replace a file by renaming a new one over it, which
+// a watch on the file itself would miss. On Linux the parent directory is watched with
+// inotify and the events are filtered by name, elsewhere the file is polled. Either way
+// a change is only reported if the size or modification time differ from the last seen
+// ones, so a burst of events from one write is reported once.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use waker::Waker;
+
+const POLL_INTERVAL_MS: u64 = 500;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Change {
+    Modified,
+    Deleted,
+}
+
+type Signature = Option<(u64, Option<SystemTime>)>;
+
+fn signature(path: &Path) -> Signature {
+    fs::metadata(path).ok().map(|m| (m.len(), m.modified().ok()))
+}
+
+/// Watches one file, sends its changes to `rx` and wakes the main loop up. The watching
+/// thread stops when the watcher is dropped.
+pub struct Watcher {
+    pub rx: mpsc::Receiver<Change>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Watcher {
+    pub fn new(path: &Path, waker: Waker) -> Watcher {
+        let (tx, rx) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let path = fs::canonicalize(path).unwrap_or(path.to_path_buf());
+        let thread_stop = stop.clone();
+        thread::spawn(move || watch(path, tx, waker, thread_stop));
+        Watcher { rx: rx, stop: stop }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Send a change if the signature of the file differs from the last one.
+fn check(path: &Path, last: &mut Signature, tx: &mpsc::Sender<Change>, waker: &Waker) -> bool {
+    let current = signature(path);
+    if current == *last {
+        return true;
+    }
+    *last = current;
+    let change = if current.is_some() { Change::Modified } else { Change::Deleted };
+    let sent = tx.send(change).is_ok();
+    waker.wake();
+    sent
+}
+
+#[cfg(target_os = "linux")]
+fn watch(path: PathBuf, tx: mpsc::Sender<Change>, waker: Waker, stop: Arc<AtomicBool>) {
+    match inotify::Inotify::new(&path) {
+        Some(inotify) => {
+            let mut last = signature(&path);
+            while !stop.load(Ordering::Relaxed) {
+                if inotify.wait(POLL_INTERVAL_MS) && !check(&path, &mut last, &tx, &waker) {
+                    break;
+                }
+            }
+        },
+        None => poll(path, tx, waker, stop),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn watch(path: PathBuf, tx: mpsc::Sender<Change>, waker: Waker, stop: Arc<AtomicBool>) {
+    poll(path, tx, waker, stop)
+}
+
+fn poll(path: PathBuf, tx: mpsc::Sender<Change>, waker: Waker, stop: Arc<AtomicBool>) {
+    let mut last = signature(&path);
+    while !stop.load(Ordering::Relaxed) {
+        thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        if !check(&path, &mut last, &tx, &waker) {
+            break;
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use std::ffi::CString;
+    use std::{mem, ptr};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+    use libc;
+
+    /// An inotify instance watching the directory of a file.
+    pub struct Inotify {
+        fd: libc::c_int,
+        name: Vec<u8>, // the file name within the directory
+    }
+
+    impl Inotify {
+        pub fn new(path: &Path) -> Option<Inotify> {
+            let (dir, name) = match (path.parent(), path.file_name()) {
+                (Some(dir), Some(name)) => (dir, name),
+                _ => return None,
+            };
+            let dir = match CString::new(dir.as_os_str().as_bytes()) { Ok(dir) => dir, Err(_) => return None };
+            let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+            if fd < 0 {
+                return None;
+            }
+            let mask = libc::IN_CLOSE_WRITE | libc::IN_MODIFY | libc::IN_ATTRIB | libc::IN_CREATE
+                | libc::IN_DELETE | libc::IN_MOVED_FROM | libc::IN_MOVED_TO | libc::IN_DELETE_SELF;
+            if unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), mask) } < 0 {
+                unsafe { libc::close(fd) };
+                return None;
+            }
+            Some(Inotify { fd: fd, name: name.as_bytes().to_vec() })
+        }
+
+        /// Wait up to `timeout_ms` for events and return true if one of them concerns
+        /// the file.
+        pub fn wait(&self, timeout_ms: u64) -> bool {
+            let mut pollfd = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+            if unsafe { libc::poll(&mut pollfd, 1, timeout_ms as libc::c_int) } <= 0 {
+                return false;
+            }
+
+            let mut found = false;
+            let mut buffer = [0u8; 4096];
+            loop {
+                let n = unsafe { libc::read(self.fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+                if n <= 0 {
+                    break;
+                }
+                let n = n as usize;
+                let header = mem::size_of::<libc::inotify_event>();
+                let mut i = 0;
+                while i + header <= n {
+                    let event: libc::inotify_event = unsafe { ptr::read_unaligned(buffer[i..].as_ptr() as *const _) };
+                    let name = &buffer[i + header..i + header + event.len as usize];
+                    let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                    if event.mask & libc::IN_DELETE_SELF != 0 || name == &self.name[..] {
+                        found = true;
+                    }
+                    i += header + event.len as usize;
+                }
+            }
+            found
+        }
+    }
+
+    impl Drop for Inotify {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.fd) };
+        }
+    }
+}